@@ -0,0 +1,91 @@
+/// Integer type used for the cells of the tape. Implemented for `u8`, `u16`, `u32` and `u64`, so
+/// the interpreter is compiled separately for each cell width.
+pub trait Cell: Copy + Eq + std::fmt::Debug {
+    const ZERO: Self;
+    const MAX: Self;
+
+    /// Truncate a value to the cell's width (two's complement for negative values)
+    fn from_i64(value: i64) -> Self;
+    fn to_u64(self) -> u64;
+    fn wrapping_add(self, other: Self) -> Self;
+    fn wrapping_mul(self, other: Self) -> Self;
+
+    /// Position of the first zero cell in `cells`
+    fn find_zero(cells: &[Self]) -> Option<usize> {
+        cells.iter().position(|&cell| cell == Self::ZERO)
+    }
+
+    /// Position of the last zero cell in `cells`
+    fn rfind_zero(cells: &[Self]) -> Option<usize> {
+        cells.iter().rposition(|&cell| cell == Self::ZERO)
+    }
+}
+
+macro_rules! impl_cell {
+    ($($ty:ty),*) => {
+        $(
+            impl Cell for $ty {
+                const ZERO: Self = 0;
+                const MAX: Self = <$ty>::MAX;
+
+                #[inline]
+                fn from_i64(value: i64) -> Self {
+                    value as $ty
+                }
+
+                #[inline]
+                fn to_u64(self) -> u64 {
+                    self as u64
+                }
+
+                #[inline]
+                fn wrapping_add(self, other: Self) -> Self {
+                    <$ty>::wrapping_add(self, other)
+                }
+
+                #[inline]
+                fn wrapping_mul(self, other: Self) -> Self {
+                    <$ty>::wrapping_mul(self, other)
+                }
+            }
+        )*
+    };
+}
+
+impl_cell!(u16, u32, u64);
+
+// Byte cells get to use `memchr` for scan loops
+impl Cell for u8 {
+    const ZERO: Self = 0;
+    const MAX: Self = u8::MAX;
+
+    #[inline]
+    fn from_i64(value: i64) -> Self {
+        value as u8
+    }
+
+    #[inline]
+    fn to_u64(self) -> u64 {
+        self as u64
+    }
+
+    #[inline]
+    fn wrapping_add(self, other: Self) -> Self {
+        u8::wrapping_add(self, other)
+    }
+
+    #[inline]
+    fn wrapping_mul(self, other: Self) -> Self {
+        u8::wrapping_mul(self, other)
+    }
+
+    #[inline]
+    fn find_zero(cells: &[Self]) -> Option<usize> {
+        memchr::memchr(0, cells)
+    }
+
+    #[inline]
+    fn rfind_zero(cells: &[Self]) -> Option<usize> {
+        memchr::memrchr(0, cells)
+    }
+}
@@ -0,0 +1,129 @@
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    IncPtr,
+    DecPtr,
+    IncVal,
+    DecVal,
+    PutChar,
+    GetChar,
+    OpenBr,
+    CloseBr,
+}
+
+impl Command {
+    pub fn from_char(ch: char) -> Option<Self> {
+        match ch {
+            '>' => Some(Command::IncPtr),
+            '<' => Some(Command::DecPtr),
+            '+' => Some(Command::IncVal),
+            '-' => Some(Command::DecVal),
+            '.' => Some(Command::PutChar),
+            ',' => Some(Command::GetChar),
+            '[' => Some(Command::OpenBr),
+            ']' => Some(Command::CloseBr),
+            _ => None,
+        }
+    }
+
+    pub fn to_char(&self) -> char {
+        match self {
+            Command::IncPtr => '>',
+            Command::DecPtr => '<',
+            Command::IncVal => '+',
+            Command::DecVal => '-',
+            Command::PutChar => '.',
+            Command::GetChar => ',',
+            Command::OpenBr => '[',
+            Command::CloseBr => ']',
+        }
+    }
+}
+
+/// Location of a character in the source code. Lines and columns start at 1, and columns count
+/// characters rather than bytes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SourcePos {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl SourcePos {
+    /// Position of the byte at `offset` in `code`
+    pub fn find(code: &str, offset: usize) -> Self {
+        let before = &code[..offset];
+        let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+        Self {
+            offset,
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+
+    /// Position of the character at `line` and `column` of `code`, or `None` if there is no
+    /// such line. Columns past the end of the line point at its line break.
+    pub fn at(code: &str, line: usize, column: usize) -> Option<Self> {
+        let line_start = match line {
+            0 => return None,
+            1 => 0,
+            _ => code.match_indices('\n').nth(line - 2)?.0 + 1,
+        };
+        let text = &code[line_start..];
+        let line_len = text.find('\n').unwrap_or(text.len());
+        let offset = text[..line_len]
+            .char_indices()
+            .nth(column.saturating_sub(1))
+            .map_or(line_len, |(offset, _)| offset);
+        Some(Self::find(code, line_start + offset))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Token {
+    pub cmd: Command,
+    pub pos: SourcePos,
+}
+
+/// Extract the commands from `code`, along with where each was found
+pub fn tokenize(code: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut column = 1;
+    for (offset, ch) in code.char_indices() {
+        if let Some(cmd) = Command::from_char(ch) {
+            tokens.push(Token { cmd, pos: SourcePos { offset, line, column } });
+        }
+        if ch == '\n' {
+            line += 1;
+            column = 1;
+        } else {
+            column += 1;
+        }
+    }
+    tokens
+}
+
+/// Quote the line of `code` that `span` starts on, underlining the span (or the part of it on
+/// that line) with carets:
+///
+/// ```text
+///   12 | >>[-<+>]<<]
+///      |           ^
+/// ```
+pub fn excerpt(code: &str, span: std::ops::Range<usize>) -> String {
+    let pos = SourcePos::find(code, span.start);
+    let line_start = code[..span.start].rfind('\n').map_or(0, |newline| newline + 1);
+    let line_end = code[span.start..].find('\n').map_or(code.len(), |newline| span.start + newline);
+    let line = code[line_start..line_end].trim_end_matches('\r');
+
+    // Keep tabs in the indentation so the carets line up with the quoted line
+    let indent: String = code[line_start..span.start]
+        .chars()
+        .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+        .collect();
+    let width = code[span.start..span.end.min(line_end).max(span.start + 1)].chars().count().max(1);
+
+    let number = pos.line.to_string();
+    let gutter = " ".repeat(number.len());
+    format!("{} | {}\n{} | {}{}\n", number, line, gutter, indent, "^".repeat(width))
+}
@@ -0,0 +1,775 @@
+use crate::cell::Cell;
+use crate::command::{Command, Token};
+use crate::error::{Error, Fault};
+use crate::io::{Input, Output};
+use crate::limits::{Interrupt, Limits, Meter};
+use crate::profile::Profile;
+use std::io::{Read, Write};
+use std::ops::Range;
+
+/// Optimized instruction. Pointer movement is sunk to the end of each basic block, so most
+/// instructions address their cell by an `offset` relative to the read/write head.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandOpt {
+    ChPtr(isize),
+    ChVal { offset: isize, amount: i64 }, // Wrapped to the cell width unless overflow is checked
+    PutChar { offset: isize },
+    GetChar { offset: isize },
+    Zero { offset: isize },
+    /// Adds the current cell multiplied by `factor` to the cell at `offset`. Emitted for
+    /// copy/multiply loops like `[->+>++<<]`, always followed by a `Zero`.
+    MulAdd { offset: isize, factor: i64 },
+    /// Moves the pointer by the given stride until it lands on a zero cell (`[>]`, `[<<<]`)
+    Scan(isize),
+    LoopForever,
+    OpenBr(usize),
+    CloseBr(usize),
+}
+
+/// What happens when a cell is incremented past its maximum value or decremented below 0
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum OverflowMode {
+    /// Wrap around, like the cell's unsigned integer type does
+    #[default]
+    Wrap,
+    /// Stop the program with an error
+    Check,
+    /// Clamp the value to the cell's range
+    Saturate,
+}
+
+/// How much `parse_with` rewrites the program
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OptLevel {
+    /// One instruction per command, for stepping through the source one command at a time
+    None,
+    /// Fold runs of commands and replace simple loops, but move the pointer where the source
+    /// does, so each instruction comes from one stretch of source and the head is always where
+    /// an unoptimized run would have it
+    Basic,
+    /// Also sink pointer movement to the end of each basic block
+    #[default]
+    Full,
+}
+
+/// Optimized program, along with the range of source bytes each instruction was generated from
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub commands: Vec<CommandOpt>,
+    pub spans: Vec<Range<usize>>,
+}
+
+/// Brackets without a partner, found while parsing
+#[derive(Debug)]
+pub struct ParseError {
+    /// Every unmatched `[` and `]`, in source order
+    pub unmatched: Vec<Token>,
+}
+
+impl ParseError {
+    /// Describe each unmatched bracket, quoting the line of `code` it's on
+    pub fn report(&self, code: &str) -> String {
+        let mut report = String::new();
+        for token in &self.unmatched {
+            let what = match token.cmd {
+                Command::OpenBr => "Unclosed '['",
+                _ => "Unexpected ']'",
+            };
+            let offset = token.pos.offset;
+            report += &format!("{} at line {}, column {}:\n", what, token.pos.line, token.pos.column);
+            report += &crate::command::excerpt(code, offset..offset + 1);
+        }
+        report
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (i, token) in self.unmatched.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "'{}' at {}:{}", token.cmd.to_char(), token.pos.line, token.pos.column)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Parse a program for cells that are `cell_bits` wide and handle overflow according to
+/// `overflow`
+pub fn parse(code: &str, cell_bits: u32, overflow: OverflowMode) -> Result<Program, ParseError> {
+    parse_with(code, cell_bits, overflow, OptLevel::Full)
+}
+
+/// Like `parse`, but only optimizing as far as `opt_level`
+pub fn parse_with(
+    code: &str,
+    cell_bits: u32,
+    overflow: OverflowMode,
+    opt_level: OptLevel,
+) -> Result<Program, ParseError> {
+    optimize_prg(&crate::command::tokenize(code), cell_bits, overflow, opt_level)
+}
+
+/// Wrap `value` to a signed number that fits in `bits` bits, i.e. the smallest change with the
+/// same effect on a cell of that width
+fn wrap(value: i64, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    (value << shift) >> shift
+}
+
+/// Extend an optional source range to include the byte at `pos`
+fn extend_span(span: Option<Range<usize>>, pos: usize) -> Range<usize> {
+    match span {
+        Some(span) => span.start..pos + 1,
+        None => pos..pos + 1,
+    }
+}
+
+struct ParseState {
+    cell_bits: u32,
+    overflow: OverflowMode,
+    opt_level: OptLevel,
+    counts: ParseCounts,
+    /// Pointer movement not yet emitted in the current basic block, and where it came from
+    offset: isize,
+    offset_span: Option<Range<usize>>,
+    /// Open brackets waiting for their `]`, with the index of their `OpenBr`
+    unres_brack: Vec<(usize, Token)>,
+    /// Closing brackets that didn't have a `[`
+    unexpected: Vec<Token>,
+    result: Vec<CommandOpt>,
+    spans: Vec<Range<usize>>,
+}
+
+/// Data structore to store the current state of parsing the code stream.
+enum ParseCounts {
+    ChVal(isize, Range<usize>),
+    None,
+}
+
+impl ParseState {
+    pub fn new(cell_bits: u32, overflow: OverflowMode, opt_level: OptLevel) -> Self {
+        Self {
+            cell_bits,
+            overflow,
+            opt_level,
+            counts: ParseCounts::None,
+            offset: 0,
+            offset_span: None,
+            unres_brack: Vec::new(),
+            unexpected: Vec::new(),
+            result: Vec::new(),
+            spans: Vec::new(),
+        }
+    }
+
+    pub fn feed(&mut self, token: &Token) {
+        let pos = token.pos.offset;
+        if self.opt_level == OptLevel::None {
+            return self.feed_unoptimized(token);
+        }
+        match token.cmd {
+            Command::IncPtr => self.ch_ptr(1, pos),
+            Command::DecPtr => self.ch_ptr(-1, pos),
+            Command::IncVal => self.ch_val(1, pos),
+            Command::DecVal => self.ch_val(-1, pos),
+            Command::PutChar => {
+                self.apply_counts();
+                self.settle_ptr();
+                self.push(CommandOpt::PutChar { offset: self.offset }, pos..pos + 1);
+            }
+            Command::GetChar => {
+                self.apply_counts();
+                self.settle_ptr();
+                self.push(CommandOpt::GetChar { offset: self.offset }, pos..pos + 1);
+            }
+            Command::OpenBr => {
+                self.end_block();
+                self.unres_brack.push((self.result.len(), token.clone()));
+                self.push(CommandOpt::OpenBr(0), pos..pos + 1);
+            }
+            Command::CloseBr => {
+                let conn = match self.unres_brack.pop() {
+                    Some((conn, _)) => conn,
+                    None => {
+                        // Keep going, so every unmatched bracket gets reported at once
+                        self.unexpected.push(token.clone());
+                        return;
+                    }
+                };
+                self.end_block();
+                let loop_span = self.spans[conn].start..pos + 1;
+                match optimize_loop(&self.result[conn + 1..], self.cell_bits, self.overflow) {
+                    Some(replacement) if replacement == [CommandOpt::Zero { offset: 0 }] => {
+                        self.truncate(conn);
+                        // Nothing moved, so fold the pointer movement leading up to the loop
+                        // back into the current block
+                        if self.opt_level == OptLevel::Full
+                            && let Some(&CommandOpt::ChPtr(amount)) = self.result.last()
+                        {
+                            self.result.pop();
+                            self.offset = amount;
+                            self.offset_span = self.spans.pop();
+                        }
+                        self.push(CommandOpt::Zero { offset: self.offset }, loop_span);
+                    }
+                    Some(replacement) => {
+                        self.truncate(conn);
+                        for cmd in replacement {
+                            self.push(cmd, loop_span.clone());
+                        }
+                    }
+                    None => {
+                        self.result[conn] = CommandOpt::OpenBr(self.result.len());
+                        self.push(CommandOpt::CloseBr(conn), pos..pos + 1);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Emit every command as its own instruction, only linking up the brackets
+    fn feed_unoptimized(&mut self, token: &Token) {
+        let pos = token.pos.offset;
+        let cmd = match token.cmd {
+            Command::IncPtr => CommandOpt::ChPtr(1),
+            Command::DecPtr => CommandOpt::ChPtr(-1),
+            Command::IncVal => CommandOpt::ChVal { offset: 0, amount: 1 },
+            Command::DecVal => CommandOpt::ChVal { offset: 0, amount: -1 },
+            Command::PutChar => CommandOpt::PutChar { offset: 0 },
+            Command::GetChar => CommandOpt::GetChar { offset: 0 },
+            Command::OpenBr => {
+                self.unres_brack.push((self.result.len(), token.clone()));
+                CommandOpt::OpenBr(0)
+            }
+            Command::CloseBr => match self.unres_brack.pop() {
+                Some((conn, _)) => {
+                    self.result[conn] = CommandOpt::OpenBr(self.result.len());
+                    CommandOpt::CloseBr(conn)
+                }
+                None => {
+                    self.unexpected.push(token.clone());
+                    return;
+                }
+            },
+        };
+        self.push(cmd, pos..pos + 1);
+    }
+
+    fn push(&mut self, cmd: CommandOpt, span: Range<usize>) {
+        self.result.push(cmd);
+        self.spans.push(span);
+    }
+
+    fn truncate(&mut self, len: usize) {
+        self.result.truncate(len);
+        self.spans.truncate(len);
+    }
+
+    pub fn ch_ptr(&mut self, count: isize, pos: usize) {
+        self.apply_counts();
+        self.offset += count;
+        self.offset_span = Some(extend_span(self.offset_span.take(), pos));
+    }
+
+    pub fn ch_val(&mut self, count: isize, pos: usize) {
+        self.settle_ptr();
+        match &mut self.counts {
+            // Without wrapping, `+-` can overflow and come back, so only fold runs going one way
+            ParseCounts::ChVal(amount, span)
+                if self.overflow == OverflowMode::Wrap || amount.signum() == count.signum() =>
+            {
+                *amount += count;
+                span.end = pos + 1;
+            }
+            _ => {
+                self.apply_counts();
+                self.counts = ParseCounts::ChVal(count, pos..pos + 1);
+            }
+        }
+    }
+
+    pub fn apply_counts(&mut self) {
+        match std::mem::replace(&mut self.counts, ParseCounts::None) {
+            ParseCounts::ChVal(count, span) => {
+                let amount = match self.overflow {
+                    OverflowMode::Wrap => wrap(count as i64, self.cell_bits),
+                    _ => count as i64,
+                };
+                if amount != 0 {
+                    self.push(CommandOpt::ChVal { offset: self.offset, amount }, span);
+                }
+            }
+            ParseCounts::None => {}
+        };
+    }
+
+    /// Below `OptLevel::Full`, emit pending pointer movement before the next instruction that
+    /// touches a cell, instead of addressing the cell by offset
+    fn settle_ptr(&mut self) {
+        if self.opt_level != OptLevel::Full && self.offset != 0 {
+            self.end_block();
+        }
+    }
+
+    /// Flush pending changes and emit the pointer movement of the current basic block
+    pub fn end_block(&mut self) {
+        self.apply_counts();
+        let span = self.offset_span.take();
+        if self.offset != 0 {
+            self.push(CommandOpt::ChPtr(self.offset), span.unwrap());
+            self.offset = 0;
+        }
+    }
+
+    pub fn get_result(&mut self) -> Result<Program, ParseError> {
+        self.end_block();
+        if !self.unres_brack.is_empty() || !self.unexpected.is_empty() {
+            let mut unmatched: Vec<Token> = self.unexpected.drain(..).collect();
+            unmatched.extend(self.unres_brack.drain(..).map(|(_, token)| token));
+            unmatched.sort_by_key(|token| token.pos.offset);
+            return Err(ParseError { unmatched });
+        }
+        Ok(Program {
+            commands: self.result.clone(),
+            spans: self.spans.clone(),
+        })
+    }
+}
+
+/// Try to replace the body of a loop (everything between the brackets) with equivalent
+/// straight-line code. Returns `None` if the loop has to be kept as-is.
+fn optimize_loop(body: &[CommandOpt], cell_bits: u32, overflow: OverflowMode) -> Option<Vec<CommandOpt>> {
+    let wrapping = overflow == OverflowMode::Wrap;
+    match body {
+        // `[]`, `[+-]` etc.
+        [] => return Some(vec![CommandOpt::LoopForever]),
+        // `[-]`, `[+]`, `[---]`: any odd step eventually wraps around to 0. Without wrapping,
+        // only `[-]` is guaranteed to end up at 0 rather than overflowing.
+        [CommandOpt::ChVal { offset: 0, amount }] if (wrapping && amount % 2 != 0) || *amount == -1 => {
+            return Some(vec![CommandOpt::Zero { offset: 0 }]);
+        }
+        [CommandOpt::ChPtr(stride)] => return Some(vec![CommandOpt::Scan(*stride)]),
+        _ => {}
+    }
+
+    // Check for copy/multiply loops like `[->+>++<<]`: only value changes with no net pointer
+    // movement, and the loop cell is decremented by exactly one. Pointer movement is usually
+    // sunk to the end of the body already, but not below `OptLevel::Full`.
+    let mut deltas: Vec<(isize, i64)> = Vec::new();
+    let mut ptr = 0;
+    for cmd in body {
+        match cmd {
+            CommandOpt::ChPtr(amount) => ptr += amount,
+            CommandOpt::ChVal { offset, amount } => {
+                let offset = &(ptr + offset);
+                match deltas.iter_mut().find(|(off, _)| off == offset) {
+                    Some((_, total)) if wrapping => *total = wrap(*total + amount, cell_bits),
+                    // Without wrapping, something like `[-->+<+]` can overflow halfway through
+                    Some(_) => return None,
+                    None => deltas.push((*offset, *amount)),
+                }
+            }
+            _ => return None,
+        }
+    }
+    if ptr != 0 || !deltas.contains(&(0, -1)) {
+        return None;
+    }
+
+    let mut result: Vec<CommandOpt> = deltas
+        .into_iter()
+        .filter(|&(offset, factor)| offset != 0 && factor != 0)
+        .map(|(offset, factor)| CommandOpt::MulAdd { offset, factor })
+        .collect();
+    result.push(CommandOpt::Zero { offset: 0 });
+    Some(result)
+}
+
+fn optimize_prg(
+    prg: &[Token],
+    cell_bits: u32,
+    overflow: OverflowMode,
+    opt_level: OptLevel,
+) -> Result<Program, ParseError> {
+    let mut state = ParseState::new(cell_bits, overflow, opt_level);
+    for token in prg {
+        state.feed(token);
+    }
+    state.get_result()
+}
+
+/// Index of the cell at `offset` from the read/write head, growing memory if needed
+#[inline(always)]
+fn cell_index<C: Cell>(mem: &mut Vec<C>, mem_ptr: usize, offset: isize) -> Result<usize, Stop> {
+    match mem_ptr.checked_add_signed(offset) {
+        Some(index) => {
+            if index >= mem.len() {
+                mem.resize(index + 1, C::ZERO); // Dynamically growing memory
+            }
+            Ok(index)
+        }
+        None => Err(Stop::Fault(Fault::PointerUnderflow)),
+    }
+}
+
+/// Add `amount` to `cell` without wrapping, clamping the result or failing depending on
+/// `overflow`
+#[inline(always)]
+fn add_unwrapped<C: Cell>(cell: C, amount: i128, overflow: OverflowMode) -> Result<C, Stop> {
+    let max = C::MAX.to_u64() as i128;
+    let value = cell.to_u64() as i128 + amount;
+    if overflow == OverflowMode::Check && !(0..=max).contains(&value) {
+        return Err(Stop::Fault(Fault::CellOverflow));
+    }
+    Ok(C::from_i64(value.clamp(0, max) as i64))
+}
+
+/// Why `run` stopped early. Faults and interrupts only become an `Error` once `Machine::run`
+/// adds where they happened.
+enum Stop {
+    Fault(Fault),
+    Interrupt(Interrupt),
+    Error(Error),
+}
+
+/// Run a program on a tape of `C` cells, reading from `input` and writing to `output`
+pub fn execute<C: Cell>(
+    prg: &Program,
+    overflow: OverflowMode,
+    input: &mut Input<impl Read>,
+    output: &mut Output<impl Write>,
+) -> Result<(), Error> {
+    Machine::<C>::new().run(prg, overflow, &Limits::default(), input, output)
+}
+
+/// State of a program running on a tape of `C` cells. It's kept between calls to `run`, so a
+/// program that ran out of fuel or time can pick up where it left off.
+#[derive(Clone, Debug)]
+pub struct Machine<C: Cell> {
+    /// Index of the next instruction to execute
+    pub prg_head: usize,
+    pub mem: Vec<C>,
+    pub mem_ptr: usize,
+}
+
+impl<C: Cell> Default for Machine<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C: Cell> Machine<C> {
+    /// Start at the first instruction, with an empty tape
+    pub fn new() -> Self {
+        Self {
+            prg_head: 0,
+            mem: vec![C::ZERO],
+            mem_ptr: 0,
+        }
+    }
+
+    /// Run `prg` from the current state until it ends, faults or hits one of `limits`. Fuel is
+    /// counted in executed instructions, and only limits this call.
+    pub fn run(
+        &mut self,
+        prg: &Program,
+        overflow: OverflowMode,
+        limits: &Limits,
+        input: &mut Input<impl Read>,
+        output: &mut Output<impl Write>,
+    ) -> Result<(), Error> {
+        let mut meter = Meter::new(limits);
+        let (cmds, meter) = (&prg.commands, &mut meter);
+        let result = if meter.is_limited() {
+            run::<C, true, false>(self, cmds, overflow, meter, &mut [], input, output)
+        } else {
+            run::<C, false, false>(self, cmds, overflow, meter, &mut [], input, output)
+        };
+        self.finish(prg, result, output)
+    }
+
+    /// Like `run`, but also counts every instruction executed into `profile`, which can be
+    /// carried over between calls to keep counting a resumed program
+    pub fn profile(
+        &mut self,
+        prg: &Program,
+        overflow: OverflowMode,
+        limits: &Limits,
+        profile: &mut Profile,
+        input: &mut Input<impl Read>,
+        output: &mut Output<impl Write>,
+    ) -> Result<(), Error> {
+        assert_eq!(profile.counts.len(), prg.commands.len(), "profile is for another program");
+        let mut meter = Meter::new(limits);
+        let (cmds, meter, counts) = (&prg.commands, &mut meter, &mut profile.counts);
+        let result = if meter.is_limited() {
+            run::<C, true, true>(self, cmds, overflow, meter, counts, input, output)
+        } else {
+            run::<C, false, true>(self, cmds, overflow, meter, counts, input, output)
+        };
+        self.finish(prg, result, output)
+    }
+
+    /// Flush `output` once `run` stopped, and turn why it stopped early into an `Error`
+    fn finish(
+        &self,
+        prg: &Program,
+        result: Result<(), Stop>,
+        output: &mut Output<impl Write>,
+    ) -> Result<(), Error> {
+        if let Err(stop) = result {
+            if let Stop::Interrupt(_) = stop {
+                // Make sure a prompt written so far shows up while the program is paused
+                output.flush().map_err(Error::Output)?;
+            }
+            return Err(self.locate(prg, stop));
+        }
+        output.flush().map_err(Error::Output)
+    }
+
+    /// Execute the next instruction of `prg`, if there is one. Unlike `run`, this doesn't flush
+    /// `output`, so callers stepping through a program should do that whenever they pause it.
+    pub fn step(
+        &mut self,
+        prg: &Program,
+        overflow: OverflowMode,
+        input: &mut Input<impl Read>,
+        output: &mut Output<impl Write>,
+    ) -> Result<(), Error> {
+        if self.is_finished(prg) {
+            return Ok(());
+        }
+        let Machine { prg_head, mem, mem_ptr } = self;
+        exec(prg_head, mem, mem_ptr, &prg.commands, overflow, input, output)
+            .map_err(|stop| self.locate(prg, stop))
+    }
+
+    /// Whether the program has run past its last instruction
+    pub fn is_finished(&self, prg: &Program) -> bool {
+        self.prg_head >= prg.commands.len()
+    }
+
+    /// Turn a `Stop` into an `Error` pointing at the current instruction
+    fn locate(&self, prg: &Program, stop: Stop) -> Error {
+        let (index, head) = (self.prg_head, self.mem_ptr);
+        match stop {
+            Stop::Fault(fault) => {
+                let span = prg.spans[index].clone();
+                Error::Runtime { fault, index, span, head }
+            }
+            Stop::Interrupt(interrupt) => {
+                let span = prg.spans[index].clone();
+                Error::Interrupted { interrupt, index, span, head }
+            }
+            Stop::Error(err) => err,
+        }
+    }
+}
+
+/// Main loop of `Machine::run`, leaving the machine at the failing instruction on error. Only
+/// counts fuel if `METERED` and executions into `counts` if `PROFILED`, to keep the plain case
+/// as fast as before.
+#[inline(always)]
+fn run<C: Cell, const METERED: bool, const PROFILED: bool>(
+    machine: &mut Machine<C>,
+    prg: &[CommandOpt],
+    overflow: OverflowMode,
+    meter: &mut Meter,
+    counts: &mut [u64],
+    input: &mut Input<impl Read>,
+    output: &mut Output<impl Write>,
+) -> Result<(), Stop> {
+    let Machine { prg_head, mem, mem_ptr } = machine;
+    let mut fuel = 0;
+
+    while *prg_head < prg.len() {
+        if METERED {
+            if fuel == 0 {
+                fuel = meter.refill().map_err(Stop::Interrupt)?;
+            }
+            fuel -= 1;
+        }
+        if PROFILED {
+            counts[*prg_head] += 1;
+        }
+        exec(prg_head, mem, mem_ptr, prg, overflow, input, output)?;
+    }
+    Ok(())
+}
+
+/// Execute the instruction at `prg_head` and move on to the next one. Takes the parts of a
+/// `Machine` separately, so `run` can keep them in locals.
+#[inline(always)]
+fn exec<C: Cell>(
+    prg_head: &mut usize,
+    mem: &mut Vec<C>,
+    mem_ptr: &mut usize,
+    prg: &[CommandOpt],
+    overflow: OverflowMode,
+    input: &mut Input<impl Read>,
+    output: &mut Output<impl Write>,
+) -> Result<(), Stop> {
+    let wrapping = overflow == OverflowMode::Wrap;
+
+    match prg[*prg_head] {
+        CommandOpt::ChPtr(amt) => *mem_ptr = cell_index(mem, *mem_ptr, amt)?,
+        CommandOpt::ChVal { offset, amount } => {
+            let idx = cell_index(mem, *mem_ptr, offset)?;
+            mem[idx] = if wrapping {
+                mem[idx].wrapping_add(C::from_i64(amount))
+            } else {
+                add_unwrapped(mem[idx], amount as i128, overflow)?
+            };
+        }
+        CommandOpt::PutChar { offset } => {
+            let idx = cell_index(mem, *mem_ptr, offset)?;
+            match output.put(mem[idx].to_u64()) {
+                Ok(()) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::InvalidData => {
+                    return Err(Stop::Fault(Fault::InvalidChar));
+                }
+                Err(err) => return Err(Stop::Error(Error::Output(err))),
+            }
+        }
+        CommandOpt::GetChar { offset } => {
+            let idx = cell_index(mem, *mem_ptr, offset)?;
+            if let Err(err) = output.flush() {
+                return Err(Stop::Error(Error::Output(err)));
+            }
+            match input.get() {
+                Ok(Some(value)) => mem[idx] = C::from_i64(value as i64),
+                Ok(None) => {}
+                Err(err) => return Err(Stop::Error(Error::Input(err))),
+            }
+        }
+        CommandOpt::OpenBr(target) => {
+            if mem[*mem_ptr] == C::ZERO {
+                *prg_head = target;
+            }
+        }
+        CommandOpt::CloseBr(target) => {
+            if mem[*mem_ptr] != C::ZERO {
+                *prg_head = target;
+            }
+        }
+        CommandOpt::Zero { offset } => {
+            let idx = cell_index(mem, *mem_ptr, offset)?;
+            mem[idx] = C::ZERO;
+        }
+        CommandOpt::MulAdd { offset, factor } => {
+            let src = mem[*mem_ptr];
+            if src != C::ZERO {
+                let target = cell_index(mem, *mem_ptr, offset)?;
+                mem[target] = if wrapping {
+                    mem[target].wrapping_add(src.wrapping_mul(C::from_i64(factor)))
+                } else {
+                    let product = src.to_u64() as i128 * factor as i128;
+                    add_unwrapped(mem[target], product, overflow)?
+                };
+            }
+        }
+        CommandOpt::Scan(stride) => match stride {
+            1 => match C::find_zero(&mem[*mem_ptr..]) {
+                Some(dist) => *mem_ptr += dist,
+                None => {
+                    // Every cell past the end is implicitly zero
+                    *mem_ptr = mem.len();
+                    mem.push(C::ZERO);
+                }
+            },
+            -1 => match C::rfind_zero(&mem[..=*mem_ptr]) {
+                Some(pos) => *mem_ptr = pos,
+                None => {
+                    return Err(Stop::Fault(Fault::PointerUnderflow));
+                }
+            },
+            _ => {
+                while mem[*mem_ptr] != C::ZERO {
+                    *mem_ptr = cell_index(mem, *mem_ptr, stride)?;
+                }
+            }
+        },
+        CommandOpt::LoopForever => {
+            // Spin on this instruction, so the run loop still gets to check its limits
+            if mem[*mem_ptr] != C::ZERO {
+                return Ok(());
+            }
+        }
+    }
+    *prg_head += 1;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::{EofBehavior, OutputEncoding};
+    use CommandOpt::*;
+
+    fn commands(code: &str) -> Vec<CommandOpt> {
+        parse(code, 8, OverflowMode::Wrap).unwrap().commands
+    }
+
+    #[test]
+    fn clear_loop_becomes_zero() {
+        assert_eq!(commands("[-]"), [Zero { offset: 0 }]);
+        assert_eq!(commands("[+]"), [Zero { offset: 0 }]);
+    }
+
+    #[test]
+    fn copy_loops_become_mul_add() {
+        assert_eq!(commands("[->+<]"), [MulAdd { offset: 1, factor: 1 }, Zero { offset: 0 }]);
+        assert_eq!(
+            commands("[->++>+++<<]"),
+            [MulAdd { offset: 1, factor: 2 }, MulAdd { offset: 2, factor: 3 }, Zero { offset: 0 }],
+        );
+    }
+
+    #[test]
+    fn scan_loops_become_scan() {
+        assert_eq!(commands("[>]"), [Scan(1)]);
+        assert_eq!(commands("[<<]"), [Scan(-2)]);
+    }
+
+    #[test]
+    fn empty_loop_becomes_loop_forever() {
+        assert_eq!(commands("[]"), [LoopForever]);
+    }
+
+    #[test]
+    fn other_loops_are_kept() {
+        let (dec, inc) = (ChVal { offset: 0, amount: -1 }, ChVal { offset: 1, amount: 1 });
+        // Net pointer movement
+        let body = [dec.clone(), inc.clone(), ChPtr(-1)];
+        assert_eq!(commands("[->+<<]"), [&[OpenBr(4)][..], &body, &[CloseBr(0)]].concat());
+        // I/O in the body
+        let body = [dec.clone(), inc.clone(), PutChar { offset: 0 }];
+        assert_eq!(commands("[->+<.]"), [&[OpenBr(4)][..], &body, &[CloseBr(0)]].concat());
+        // Loop cell not decremented by exactly one
+        let body = [ChVal { offset: 0, amount: -2 }, inc];
+        assert_eq!(commands("[-->+<]"), [&[OpenBr(3)][..], &body, &[CloseBr(0)]].concat());
+    }
+
+    #[test]
+    fn loop_forever_spins_until_interrupted() {
+        let prg = parse("+[]", 8, OverflowMode::Wrap).unwrap();
+        let mut input = Input::new(&[][..], EofBehavior::Zero, false);
+        let mut output = Output::new(Vec::new(), OutputEncoding::Raw);
+        let mut machine = Machine::<u8>::new();
+        let limits = Limits { fuel: Some(100), deadline: None };
+        let result = machine.run(&prg, OverflowMode::Wrap, &limits, &mut input, &mut output);
+        let Err(Error::Interrupted { interrupt, index, .. }) = result else {
+            panic!("expected an interrupt, got {:?}", result);
+        };
+        assert_eq!((interrupt, index), (Interrupt::OutOfFuel, 1));
+
+        // On a zero cell it's passed straight away, even as the first instruction
+        let prg = parse("[]", 8, OverflowMode::Wrap).unwrap();
+        execute::<u8>(&prg, OverflowMode::Wrap, &mut input, &mut output).unwrap();
+    }
+}
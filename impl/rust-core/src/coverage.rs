@@ -0,0 +1,235 @@
+use crate::command::SourcePos;
+use crate::command_opt::Program;
+use crate::profile::Profile;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Which lines and loops of a program ran, and how often. Collected from the profile of a
+/// program parsed with `OptLevel::None`, so every command has its own count, and can be added up
+/// over many runs.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Coverage {
+    /// Executions of each line with commands on it, counting its most executed command
+    pub lines: BTreeMap<usize, u64>,
+    /// Counts for each loop, in the order they appear in the source
+    pub loops: Vec<LoopCoverage>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoopCoverage {
+    /// Position of the `[`
+    pub line: usize,
+    pub column: usize,
+    /// Number of times the loop was reached, which is also how often it was left
+    pub entries: u64,
+    /// Number of times the body ran
+    pub iterations: u64,
+}
+
+impl Coverage {
+    /// Coverage of one run of `prg`, generated from `code`
+    pub fn new(profile: &Profile, prg: &Program, code: &str) -> Self {
+        // Offset of the start of each line, to look up many commands' lines quickly
+        let line_starts: Vec<usize> = std::iter::once(0)
+            .chain(code.match_indices('\n').map(|(offset, _)| offset + 1))
+            .collect();
+        let mut lines = BTreeMap::new();
+        for (span, &count) in prg.spans.iter().zip(&profile.counts) {
+            let line = line_starts.partition_point(|&start| start <= span.start);
+            let executions = lines.entry(line).or_insert(0);
+            *executions = count.max(*executions);
+        }
+        let loops = profile
+            .loops(prg)
+            .into_iter()
+            .map(|stats| {
+                let pos = SourcePos::find(code, stats.span.start);
+                LoopCoverage {
+                    line: pos.line,
+                    column: pos.column,
+                    entries: stats.entries,
+                    iterations: stats.iterations,
+                }
+            })
+            .collect();
+        Self { lines, loops }
+    }
+
+    /// Add the counts of the record for `source` in the lcov tracefile `lcov`, if it has one.
+    /// Loops are matched up by their order in the source, so the counts only make sense if the
+    /// source hasn't changed in between.
+    pub fn merge_lcov(&mut self, lcov: &str, source: &str) {
+        let Some(record) = find_record(lcov, source) else {
+            return;
+        };
+        for line in record.lines() {
+            let Some((kind, fields)) = line.split_once(':') else {
+                continue;
+            };
+            let fields: Vec<&str> = fields.split(',').collect();
+            match (kind, fields.as_slice()) {
+                ("DA", [line, count, ..]) => {
+                    if let (Ok(line), Ok(count)) = (line.parse(), count.parse::<u64>()) {
+                        *self.lines.entry(line).or_insert(0) += count;
+                    }
+                }
+                ("BRDA", [_, block, branch, taken]) => {
+                    let (Ok(block), Ok(branch)) = (block.parse::<usize>(), branch.parse()) else {
+                        continue;
+                    };
+                    // `-` means the branch was never reached
+                    let taken: u64 = taken.parse().unwrap_or(0);
+                    match (self.loops.get_mut(block), branch) {
+                        (Some(stats), 0) => stats.iterations += taken,
+                        (Some(stats), 1) => stats.entries += taken,
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// lcov tracefile record for the program at `source`. Every loop is a branch with two
+    /// directions: into the body (taken once per iteration) and past the loop (once per entry).
+    pub fn to_lcov(&self, source: &str) -> String {
+        let mut lcov = format!("TN:\nSF:{}\n", source);
+        for (block, stats) in self.loops.iter().enumerate() {
+            let (iterations, entries) = match stats.entries {
+                0 => (String::from("-"), String::from("-")),
+                entries => (stats.iterations.to_string(), entries.to_string()),
+            };
+            writeln!(lcov, "BRDA:{},{},0,{}", stats.line, block, iterations).unwrap();
+            writeln!(lcov, "BRDA:{},{},1,{}", stats.line, block, entries).unwrap();
+        }
+        let branches_hit = self
+            .loops
+            .iter()
+            .map(|stats| (stats.iterations > 0) as usize + (stats.entries > 0) as usize)
+            .sum::<usize>();
+        writeln!(lcov, "BRF:{}\nBRH:{}", self.loops.len() * 2, branches_hit).unwrap();
+        for (line, count) in &self.lines {
+            writeln!(lcov, "DA:{},{}", line, count).unwrap();
+        }
+        let lines_hit = self.lines.values().filter(|&&count| count > 0).count();
+        writeln!(lcov, "LF:{}\nLH:{}\nend_of_record", self.lines.len(), lines_hit).unwrap();
+        lcov
+    }
+
+    /// Replace the record for `source` in the lcov tracefile `lcov` with this coverage, keeping
+    /// the records for other files
+    pub fn update_lcov(&self, lcov: &str, source: &str) -> String {
+        let mut updated = String::new();
+        for record in records(lcov).filter(|record| record_source(record) != Some(source)) {
+            updated.push_str(record.trim_start());
+            updated.push_str("end_of_record\n");
+        }
+        updated + &self.to_lcov(source)
+    }
+
+    /// `code` with the execution count of each line in front of it, like gcov: `-` for lines
+    /// without commands and `#####` for lines that never ran. Loops that were reached but never
+    /// ran their body are pointed out below their line.
+    pub fn annotate(&self, code: &str) -> String {
+        let mut annotated = String::new();
+        for (index, text) in code.lines().enumerate() {
+            let line = index + 1;
+            let count = match self.lines.get(&line) {
+                None => String::from("-"),
+                Some(0) => String::from("#####"),
+                Some(count) => count.to_string(),
+            };
+            writeln!(annotated, "{:>9}:{:>5}:{}", count, line, text).unwrap();
+            for stats in &self.loops {
+                if stats.line == line && stats.entries > 0 && stats.iterations == 0 {
+                    // Keep tabs so the caret lines up with the quoted line
+                    let indent: String = text
+                        .chars()
+                        .take(stats.column - 1)
+                        .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+                        .collect();
+                    writeln!(annotated, "{:>16}{}^ body never ran", "", indent).unwrap();
+                }
+            }
+        }
+        annotated
+    }
+}
+
+/// Records of an lcov tracefile, without their `end_of_record` lines
+fn records(lcov: &str) -> impl Iterator<Item = &str> {
+    lcov.split("end_of_record\n").filter(|record| !record.trim().is_empty())
+}
+
+fn record_source(record: &str) -> Option<&str> {
+    record.lines().find_map(|line| line.strip_prefix("SF:"))
+}
+
+fn find_record<'a>(lcov: &'a str, source: &str) -> Option<&'a str> {
+    records(lcov).find(|record| record_source(record) == Some(source))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_opt::{parse_with, Machine, OptLevel, OverflowMode};
+    use crate::io::{EofBehavior, Input, Output, OutputEncoding};
+    use crate::limits::Limits;
+
+    const CODE: &str = ",\n[>+<-]\n>.\n";
+
+    /// Coverage of running `CODE` on `input`
+    fn run(input: &[u8]) -> Coverage {
+        let prg = parse_with(CODE, 8, OverflowMode::Wrap, OptLevel::None).unwrap();
+        let mut profile = Profile::new(&prg);
+        let mut input = Input::new(input, EofBehavior::Zero, false);
+        let mut output = Output::new(Vec::new(), OutputEncoding::Raw);
+        let (overflow, limits) = (OverflowMode::Wrap, Limits::default());
+        Machine::<u8>::new()
+            .profile(&prg, overflow, &limits, &mut profile, &mut input, &mut output)
+            .unwrap();
+        Coverage::new(&profile, &prg, CODE)
+    }
+
+    /// Lines of the record for `source` with the given kind, like `DA`
+    fn fields<'a>(lcov: &'a str, source: &str, kind: &str) -> Vec<&'a str> {
+        let prefix = format!("{}:", kind);
+        let record = find_record(lcov, source).expect("record for the source");
+        record.lines().filter_map(|line| line.strip_prefix(prefix.as_str())).collect()
+    }
+
+    #[test]
+    fn counts_lines_and_loops() {
+        let coverage = run(&[3]);
+        assert_eq!(coverage.lines, BTreeMap::from([(1, 1), (2, 3), (3, 1)]));
+        let stats = &coverage.loops[0];
+        assert_eq!((stats.line, stats.column, stats.entries, stats.iterations), (2, 1, 1, 3));
+    }
+
+    #[test]
+    fn runs_add_up_in_tracefile() {
+        let other = "TN:\nSF:/other.bf\nDA:1,7\nLF:1\nLH:1\nend_of_record\n";
+        let first = run(&[3]).update_lcov(other, "/prog.bf");
+
+        // The loop body doesn't run without input
+        let mut second = run(&[]);
+        assert_eq!(second.loops[0].iterations, 0);
+        second.merge_lcov(&first, "/prog.bf");
+        let lcov = second.update_lcov(&first, "/prog.bf");
+
+        assert_eq!(fields(&lcov, "/prog.bf", "DA"), ["1,2", "2,4", "3,2"]);
+        assert_eq!(fields(&lcov, "/prog.bf", "BRDA"), ["2,0,0,3", "2,0,1,2"]);
+        assert_eq!(fields(&lcov, "/prog.bf", "LH"), ["3"]);
+        // Other files' records are kept as they were
+        assert_eq!(fields(&lcov, "/other.bf", "DA"), ["1,7"]);
+        assert_eq!(lcov.matches("end_of_record").count(), 2);
+    }
+
+    #[test]
+    fn annotate_marks_loops_that_never_ran() {
+        let annotated = run(&[]).annotate(CODE);
+        let lines: Vec<&str> = annotated.lines().collect();
+        assert_eq!(lines[1], "        1:    2:[>+<-]");
+        assert_eq!(lines[2], "                ^ body never ran");
+    }
+}
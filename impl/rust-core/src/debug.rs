@@ -0,0 +1,681 @@
+use crate::cell::Cell;
+use crate::command_opt::{
+    parse_with, CommandOpt, Machine, OptLevel, OverflowMode, ParseError, Program,
+};
+use crate::error::Error;
+use crate::io::{Input, Output};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io::{Read, Write};
+use std::ops::Range;
+
+/// Character that sets a breakpoint on the command following it, like in many other BF
+/// debuggers. It isn't a command, so programs containing it still run normally elsewhere, but
+/// plenty of programs use it in comments, so `Debugger::new` leaves it alone.
+pub const BREAKPOINT_CHAR: char = '#';
+
+/// Number of steps the journal remembers unless told otherwise, taking up about 64 MB
+pub const DEFAULT_JOURNAL_LIMIT: usize = 1 << 20;
+
+/// Number of cells edits through the debugger may grow the tape to. Programs can still grow it
+/// further on their own, but a client asking for a far-off cell shouldn't allocate gigabytes.
+pub const EDIT_LIMIT: usize = 1 << 24;
+
+/// An edit asked for a cell past both the end of the tape and `EDIT_LIMIT`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutOfTape;
+
+impl std::fmt::Display for OutOfTape {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Cells past {} can't be edited", EDIT_LIMIT - 1)
+    }
+}
+
+/// Why the debugger paused the program
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pause {
+    /// Took as many steps as asked for
+    Stepped,
+    /// Reached the command with a breakpoint at this instruction index
+    Breakpoint(usize),
+    /// A watched cell changed
+    Watchpoint { cell: usize, old: u64, new: u64 },
+    /// Ran past the last command
+    Finished,
+    /// Stepped back as far as the journal goes
+    Start,
+}
+
+/// What `Debugger::step` and friends count as one step
+enum Stride {
+    Command,
+    /// Only count reaching the start of an optimized instruction
+    Op,
+    /// Count commands, but stop early once the next one is outside this range
+    Within(Range<usize>),
+}
+
+/// A cell one step wrote to, with its value before and after
+#[derive(Clone, Copy, Debug)]
+struct CellWrite {
+    cell: usize,
+    old: u64,
+    new: u64,
+}
+
+/// What one step changed, so it can be undone and redone
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    /// Instruction index and head position before the step
+    before: (usize, usize),
+    /// Instruction index and head position after the step
+    after: (usize, usize),
+    write: Option<CellWrite>,
+}
+
+/// The most recent steps taken. Steps that were undone stay in the journal and are replayed
+/// when going forward again, so input isn't read and output isn't written a second time.
+#[derive(Debug)]
+struct Journal {
+    entries: VecDeque<Entry>,
+    /// Number of entries that are currently applied, i.e. not undone
+    applied: usize,
+    limit: usize,
+}
+
+impl Journal {
+    fn record(&mut self, entry: Entry) {
+        if self.limit == 0 {
+            return;
+        }
+        // The limit may have been lowered since the last step
+        while self.entries.len() >= self.limit {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+        self.applied = self.entries.len();
+    }
+
+    fn undo(&mut self) -> Option<Entry> {
+        self.applied = self.applied.checked_sub(1)?;
+        Some(self.entries[self.applied])
+    }
+
+    fn redo(&mut self) -> Option<Entry> {
+        let entry = *self.entries.get(self.applied)?;
+        self.applied += 1;
+        Some(entry)
+    }
+}
+
+/// Runs a program one command at a time, pausing at breakpoints and when watched cells change.
+///
+/// The program is executed without optimizations (`OptLevel::None`), so every command can be
+/// stepped through on its own and instruction indices map one-to-one to commands. To step over
+/// what the optimizer would turn into a single instruction instead, it also keeps the program
+/// parsed at `OptLevel::Basic`, whose instructions each cover one stretch of source.
+///
+/// Every step is recorded in a journal of cell writes and head moves, so the debugger can also
+/// go backwards, up to a limit of `DEFAULT_JOURNAL_LIMIT` steps by default.
+pub struct Debugger<C: Cell> {
+    /// Unoptimized program being executed
+    pub program: Program,
+    /// Same program at `OptLevel::Basic`
+    pub optimized: Program,
+    /// For each command, the optimized instruction starting at it, if any
+    op_starts: Vec<Option<usize>>,
+    pub machine: Machine<C>,
+    overflow: OverflowMode,
+    /// Instruction indices to pause before
+    breakpoints: BTreeSet<usize>,
+    /// Watched cells and the value they had when last checked
+    watchpoints: BTreeMap<usize, u64>,
+    journal: Journal,
+    /// Number of commands executed so far
+    pub steps: u64,
+    /// Instruction index the program last paused at, so continuing from a breakpoint doesn't
+    /// stop at it again right away
+    paused_at: Option<usize>,
+}
+
+impl<C: Cell> Debugger<C> {
+    /// Parse `code` for debugging
+    pub fn new(code: &str, overflow: OverflowMode) -> Result<Self, ParseError> {
+        let cell_bits = C::MAX.to_u64().count_ones();
+        let program = parse_with(code, cell_bits, overflow, OptLevel::None)?;
+        let optimized = parse_with(code, cell_bits, overflow, OptLevel::Basic)?;
+
+        let mut op_starts = vec![None; program.commands.len()];
+        for (op, span) in optimized.spans.iter().enumerate().rev() {
+            if let Some(index) = Self::index_at(&program, span.start) {
+                op_starts[index] = Some(op);
+            }
+        }
+
+        Ok(Self {
+            program,
+            optimized,
+            op_starts,
+            machine: Machine::new(),
+            overflow,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeMap::new(),
+            journal: Journal {
+                entries: VecDeque::new(),
+                applied: 0,
+                limit: DEFAULT_JOURNAL_LIMIT,
+            },
+            steps: 0,
+            paused_at: None,
+        })
+    }
+
+    /// Index of the first command at or after byte `offset` of the source
+    pub fn command_at(&self, offset: usize) -> Option<usize> {
+        Self::index_at(&self.program, offset)
+    }
+
+    fn index_at(program: &Program, offset: usize) -> Option<usize> {
+        let index = program.spans.partition_point(|span| span.start < offset);
+        (index < program.spans.len()).then_some(index)
+    }
+
+    /// Remember at most `limit` steps from now on, to bound how much memory the journal uses.
+    /// 0 turns it off, so the program can't be stepped back at all.
+    pub fn set_journal_limit(&mut self, limit: usize) {
+        self.journal.limit = limit;
+    }
+
+    /// Earliest step count the journal can go back to
+    pub fn earliest_step(&self) -> u64 {
+        self.steps - self.journal.applied as u64
+    }
+
+    /// Go back to the start of the program with an empty tape, keeping breakpoints and
+    /// watchpoints
+    pub fn restart(&mut self) {
+        self.machine = Machine::new();
+        self.journal.entries.clear();
+        self.journal.applied = 0;
+        self.steps = 0;
+        self.paused_at = None;
+        for value in self.watchpoints.values_mut() {
+            *value = 0;
+        }
+    }
+
+    /// Source range of the command that executes next, or `None` once the program finished
+    pub fn current_span(&self) -> Option<Range<usize>> {
+        self.program.spans.get(self.machine.prg_head).cloned()
+    }
+
+    /// Index into `optimized` of the instruction starting at the next command, if any
+    pub fn current_op(&self) -> Option<usize> {
+        self.op_starts.get(self.machine.prg_head).copied().flatten()
+    }
+
+    /// Set a breakpoint on the first command at or after byte `offset` of the source. Returns
+    /// its instruction index, or `None` if there are no commands after `offset`.
+    pub fn add_breakpoint(&mut self, offset: usize) -> Option<usize> {
+        let index = Self::index_at(&self.program, offset)?;
+        self.breakpoints.insert(index);
+        Some(index)
+    }
+
+    /// Remove all breakpoints
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    /// Set a breakpoint on the command after each `BREAKPOINT_CHAR` in `code`, which should be
+    /// the source the debugger was created from
+    pub fn add_marked_breakpoints(&mut self, code: &str) {
+        for (offset, _) in code.match_indices(BREAKPOINT_CHAR) {
+            self.add_breakpoint(offset);
+        }
+    }
+
+    /// Remove the breakpoint on the first command at or after byte `offset`. Returns whether
+    /// there was one.
+    pub fn remove_breakpoint(&mut self, offset: usize) -> bool {
+        Self::index_at(&self.program, offset).is_some_and(|index| self.breakpoints.remove(&index))
+    }
+
+    /// Instruction indices with a breakpoint, in order
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Pause whenever the value of `cell` changes
+    pub fn add_watchpoint(&mut self, cell: usize) {
+        let value = self.cell(cell);
+        self.watchpoints.insert(cell, value);
+    }
+
+    /// Stop watching `cell`. Returns whether it was watched.
+    pub fn remove_watchpoint(&mut self, cell: usize) -> bool {
+        self.watchpoints.remove(&cell).is_some()
+    }
+
+    /// Watched cells, in order
+    pub fn watchpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.watchpoints.keys().copied()
+    }
+
+    /// Value of `cell`, including cells past the end of the tape the program hasn't touched yet
+    pub fn cell(&self, cell: usize) -> u64 {
+        self.machine.mem.get(cell).map_or(0, |value| value.to_u64())
+    }
+
+    /// Change the value of `cell`, truncated to the cell width. Like the other edits, this
+    /// isn't journaled: stepping back over it is fine, but the steps undone before it can't be
+    /// replayed anymore.
+    pub fn set_cell(&mut self, cell: usize, value: u64) -> Result<(), OutOfTape> {
+        self.grow_to(cell)?;
+        self.machine.mem[cell] = C::from_i64(value as i64);
+        let value = self.cell(cell);
+        if let Some(watched) = self.watchpoints.get_mut(&cell) {
+            *watched = value;
+        }
+        self.discard_redo();
+        Ok(())
+    }
+
+    /// Move the read/write head to `cell`, growing the tape to cover it
+    pub fn set_head(&mut self, cell: usize) -> Result<(), OutOfTape> {
+        self.grow_to(cell)?;
+        self.machine.mem_ptr = cell;
+        self.discard_redo();
+        Ok(())
+    }
+
+    /// Grow the tape to include `cell`, if it's within `EDIT_LIMIT`
+    fn grow_to(&mut self, cell: usize) -> Result<(), OutOfTape> {
+        let mem = &mut self.machine.mem;
+        if cell >= mem.len() {
+            if cell >= EDIT_LIMIT {
+                return Err(OutOfTape);
+            }
+            mem.resize(cell + 1, C::ZERO);
+        }
+        Ok(())
+    }
+
+    /// Continue at instruction `index` instead of the current one
+    pub fn jump(&mut self, index: usize) {
+        self.machine.prg_head = index.min(self.program.commands.len());
+        self.paused_at = None;
+        self.discard_redo();
+    }
+
+    /// Drop the steps that were undone, since they no longer follow from the current state
+    fn discard_redo(&mut self) {
+        self.journal.entries.truncate(self.journal.applied);
+    }
+
+    /// Execute up to `count` commands
+    pub fn step(
+        &mut self,
+        count: u64,
+        input: &mut Input<impl Read>,
+        output: &mut Output<impl Write>,
+    ) -> Result<Pause, Error> {
+        self.resume(count, Stride::Command, input, output)
+    }
+
+    /// Execute up to `count` optimized instructions, i.e. run until the start of the next
+    /// instruction in `optimized` that many times. Loops the optimizer replaces, like `[-]`,
+    /// are stepped over as a whole.
+    pub fn step_op(
+        &mut self,
+        count: u64,
+        input: &mut Input<impl Read>,
+        output: &mut Output<impl Write>,
+    ) -> Result<Pause, Error> {
+        self.resume(count, Stride::Op, input, output)
+    }
+
+    /// Run until a breakpoint, watchpoint or the end of the program
+    pub fn cont(
+        &mut self,
+        input: &mut Input<impl Read>,
+        output: &mut Output<impl Write>,
+    ) -> Result<Pause, Error> {
+        self.resume(u64::MAX, Stride::Command, input, output)
+    }
+
+    /// Run until the next command is outside the instruction indices in `range`, like after
+    /// leaving one of the `loops`, or until a breakpoint or watchpoint. Stops after `limit`
+    /// commands at the latest.
+    pub fn run_while_in(
+        &mut self,
+        range: Range<usize>,
+        limit: u64,
+        input: &mut Input<impl Read>,
+        output: &mut Output<impl Write>,
+    ) -> Result<Pause, Error> {
+        self.resume(limit, Stride::Within(range), input, output)
+    }
+
+    /// Instruction indices of the loops around the next command, outermost first, from the
+    /// `[` to the `]`
+    pub fn loops(&self) -> Vec<Range<usize>> {
+        let head = self.machine.prg_head;
+        self.program.commands[..head.min(self.program.commands.len())]
+            .iter()
+            .enumerate()
+            .filter_map(|(index, cmd)| match *cmd {
+                CommandOpt::OpenBr(close) if head <= close => Some(index..close + 1),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn resume(
+        &mut self,
+        count: u64,
+        stride: Stride,
+        input: &mut Input<impl Read>,
+        output: &mut Output<impl Write>,
+    ) -> Result<Pause, Error> {
+        let result = self.run_until(count, stride, input, output);
+        self.paused_at = Some(self.machine.prg_head);
+        output.flush().map_err(Error::Output)?;
+        result
+    }
+
+    fn run_until(
+        &mut self,
+        mut count: u64,
+        stride: Stride,
+        input: &mut Input<impl Read>,
+        output: &mut Output<impl Write>,
+    ) -> Result<Pause, Error> {
+        // Stop at a breakpoint on the next command before taking any steps, like one on the
+        // program's first command, unless the program is already paused there
+        let head = self.machine.prg_head;
+        if count > 0 && self.paused_at != Some(head) && self.breakpoints.contains(&head) {
+            return Ok(Pause::Breakpoint(head));
+        }
+        while count > 0 {
+            if self.machine.is_finished(&self.program) {
+                return Ok(Pause::Finished);
+            }
+            self.forward(input, output)?;
+
+            for (&cell, old) in self.watchpoints.iter_mut() {
+                let new = self.machine.mem.get(cell).map_or(0, |value| value.to_u64());
+                if new != *old {
+                    let old = std::mem::replace(old, new);
+                    return Ok(Pause::Watchpoint { cell, old, new });
+                }
+            }
+            let head = self.machine.prg_head;
+            if self.breakpoints.contains(&head) {
+                return Ok(Pause::Breakpoint(head));
+            }
+            match &stride {
+                Stride::Op
+                    if self.current_op().is_none() && !self.machine.is_finished(&self.program) => {}
+                Stride::Within(range) if !range.contains(&head) => return Ok(Pause::Stepped),
+                _ => count -= 1,
+            }
+        }
+        Ok(match self.machine.is_finished(&self.program) {
+            true => Pause::Finished,
+            false => Pause::Stepped,
+        })
+    }
+
+    /// Undo up to `count` steps
+    pub fn step_back(&mut self, count: u64) -> Pause {
+        self.run_back(count, None)
+    }
+
+    /// Run backwards until a breakpoint, a watchpoint or the start of the journal
+    pub fn reverse_cont(&mut self) -> Pause {
+        self.run_back(u64::MAX, None)
+    }
+
+    /// Run backwards to just before the last write to `cell`, which is reported like a
+    /// watchpoint. Breakpoints and watchpoints on the way are ignored.
+    pub fn back_to_write(&mut self, cell: usize) -> Pause {
+        self.run_back(u64::MAX, Some(cell))
+    }
+
+    /// Go backwards or forwards to the point where `step` commands had been executed, ignoring
+    /// breakpoints and watchpoints
+    pub fn goto(
+        &mut self,
+        step: u64,
+        input: &mut Input<impl Read>,
+        output: &mut Output<impl Write>,
+    ) -> Result<Pause, Error> {
+        let mut result = Ok(Pause::Stepped);
+        while self.steps > step {
+            if self.backward().is_none() {
+                result = Ok(Pause::Start);
+                break;
+            }
+        }
+        while self.steps < step {
+            if self.machine.is_finished(&self.program) {
+                result = Ok(Pause::Finished);
+                break;
+            }
+            if let Err(err) = self.forward(input, output) {
+                result = Err(err);
+                break;
+            }
+        }
+        for (&cell, value) in self.watchpoints.iter_mut() {
+            *value = self.machine.mem.get(cell).map_or(0, |value| value.to_u64());
+        }
+        self.paused_at = Some(self.machine.prg_head);
+        output.flush().map_err(Error::Output)?;
+        result
+    }
+
+    fn run_back(&mut self, count: u64, target: Option<usize>) -> Pause {
+        let pause = self.undo_until(count, target);
+        self.paused_at = Some(self.machine.prg_head);
+        pause
+    }
+
+    fn undo_until(&mut self, mut count: u64, target: Option<usize>) -> Pause {
+        while count > 0 {
+            let entry = match self.backward() {
+                Some(entry) => entry,
+                None => return Pause::Start,
+            };
+            if let Some(CellWrite { cell, old, new }) = entry.write {
+                let watched = match self.watchpoints.get_mut(&cell) {
+                    Some(value) => {
+                        *value = old;
+                        old != new
+                    }
+                    None => false,
+                };
+                if target == Some(cell) || (target.is_none() && watched) {
+                    return Pause::Watchpoint { cell, old, new };
+                }
+            }
+            let head = self.machine.prg_head;
+            if target.is_none() && self.breakpoints.contains(&head) {
+                return Pause::Breakpoint(head);
+            }
+            count -= 1;
+        }
+        Pause::Stepped
+    }
+
+    /// Take one step, replaying it from the journal if it was undone before
+    fn forward(
+        &mut self,
+        input: &mut Input<impl Read>,
+        output: &mut Output<impl Write>,
+    ) -> Result<(), Error> {
+        if let Some(entry) = self.journal.redo() {
+            self.restore(entry.after, entry.write.map(|write| (write.cell, write.new)));
+        } else {
+            let before = (self.machine.prg_head, self.machine.mem_ptr);
+            // Unoptimized, only these write to a cell, and only to the one under the head
+            let writes = matches!(
+                self.program.commands[before.0],
+                CommandOpt::ChVal { .. } | CommandOpt::GetChar { .. }
+            );
+            let old = self.cell(before.1);
+            self.machine.step(&self.program, self.overflow, input, output)?;
+            let write = writes.then(|| CellWrite { cell: before.1, old, new: self.cell(before.1) });
+            let after = (self.machine.prg_head, self.machine.mem_ptr);
+            self.journal.record(Entry { before, after, write });
+        }
+        self.steps += 1;
+        Ok(())
+    }
+
+    /// Undo the last step, if the journal still has it
+    fn backward(&mut self) -> Option<Entry> {
+        let entry = self.journal.undo()?;
+        self.restore(entry.before, entry.write.map(|write| (write.cell, write.old)));
+        self.steps -= 1;
+        Some(entry)
+    }
+
+    fn restore(&mut self, (prg_head, mem_ptr): (usize, usize), write: Option<(usize, u64)>) {
+        self.machine.prg_head = prg_head;
+        self.machine.mem_ptr = mem_ptr;
+        if let Some((cell, value)) = write {
+            // The cell was written before, so the tape already reaches it
+            self.machine.mem[cell] = C::from_i64(value as i64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::{EofBehavior, OutputEncoding};
+
+    fn entry(step: usize) -> Entry {
+        let write = CellWrite { cell: 0, old: step as u64, new: step as u64 + 1 };
+        Entry { before: (step, 0), after: (step + 1, 0), write: Some(write) }
+    }
+
+    fn journal(limit: usize) -> Journal {
+        Journal { entries: VecDeque::new(), applied: 0, limit }
+    }
+
+    /// Steps the entries in `journal` were recorded for
+    fn recorded(journal: &Journal) -> Vec<usize> {
+        journal.entries.iter().map(|entry| entry.before.0).collect()
+    }
+
+    #[test]
+    fn journal_undoes_and_redoes() {
+        let mut journal = journal(10);
+        for step in 0..3 {
+            journal.record(entry(step));
+        }
+        assert_eq!(journal.undo().map(|entry| entry.before.0), Some(2));
+        assert_eq!(journal.undo().map(|entry| entry.before.0), Some(1));
+        assert_eq!(journal.redo().map(|entry| entry.after.0), Some(2));
+        assert_eq!(journal.applied, 2);
+        assert_eq!(journal.undo().map(|entry| entry.before.0), Some(1));
+        assert_eq!(journal.undo().map(|entry| entry.before.0), Some(0));
+        assert!(journal.undo().is_none());
+    }
+
+    #[test]
+    fn journal_drops_oldest_steps() {
+        let mut journal = journal(3);
+        for step in 0..5 {
+            journal.record(entry(step));
+        }
+        assert_eq!(recorded(&journal), [2, 3, 4]);
+        assert_eq!(journal.applied, 3);
+
+        // A lower limit takes effect with the next step
+        journal.limit = 2;
+        journal.record(entry(5));
+        assert_eq!(recorded(&journal), [4, 5]);
+
+        journal.limit = 0;
+        journal.record(entry(6));
+        assert_eq!(recorded(&journal), [4, 5]);
+    }
+
+    #[test]
+    fn step_back_past_journal_limit() {
+        let mut debugger = debugger("+++++");
+        debugger.set_journal_limit(2);
+        let (mut input, mut output) = io();
+        assert_eq!(debugger.cont(&mut input, &mut output).unwrap(), Pause::Finished);
+        assert_eq!(debugger.earliest_step(), 3);
+        assert_eq!(debugger.step_back(5), Pause::Start);
+        assert_eq!((debugger.steps, debugger.cell(0)), (3, 3));
+        // Going forward again replays the journal
+        assert_eq!(debugger.step(1, &mut input, &mut output).unwrap(), Pause::Stepped);
+        assert_eq!((debugger.steps, debugger.cell(0)), (4, 4));
+    }
+
+    fn debugger(code: &str) -> Debugger<u8> {
+        let mut debugger = Debugger::new(code, OverflowMode::Wrap).unwrap();
+        debugger.add_marked_breakpoints(code);
+        debugger
+    }
+
+    fn io() -> (Input<&'static [u8]>, Output<Vec<u8>>) {
+        let input = Input::new(&[][..], EofBehavior::Zero, false);
+        (input, Output::new(Vec::new(), OutputEncoding::Raw))
+    }
+
+    #[test]
+    fn breakpoint_on_first_command_is_hit() {
+        let mut debugger = debugger("#+#+");
+        let (mut input, mut output) = io();
+        assert_eq!(debugger.cont(&mut input, &mut output).unwrap(), Pause::Breakpoint(0));
+        assert_eq!(debugger.steps, 0);
+        // Continuing from there moves on to the next breakpoint
+        assert_eq!(debugger.cont(&mut input, &mut output).unwrap(), Pause::Breakpoint(1));
+        assert_eq!(debugger.cont(&mut input, &mut output).unwrap(), Pause::Finished);
+
+        // Again after restarting
+        debugger.restart();
+        assert_eq!(debugger.cont(&mut input, &mut output).unwrap(), Pause::Breakpoint(0));
+    }
+
+    #[test]
+    fn continue_after_stepping_back_onto_breakpoint() {
+        let mut debugger = debugger("+#++");
+        let (mut input, mut output) = io();
+        assert_eq!(debugger.cont(&mut input, &mut output).unwrap(), Pause::Breakpoint(1));
+        assert_eq!(debugger.cont(&mut input, &mut output).unwrap(), Pause::Finished);
+        assert_eq!(debugger.reverse_cont(), Pause::Breakpoint(1));
+        assert_eq!(debugger.cont(&mut input, &mut output).unwrap(), Pause::Finished);
+        assert_eq!(debugger.cell(0), 3);
+    }
+
+    #[test]
+    fn edits_grow_the_tape() {
+        let mut debugger = debugger("[-]+");
+        let (mut input, mut output) = io();
+        debugger.set_head(100).unwrap();
+        debugger.set_cell(100, 2).unwrap();
+        assert_eq!(debugger.cont(&mut input, &mut output).unwrap(), Pause::Finished);
+        assert_eq!((debugger.machine.mem_ptr, debugger.cell(100)), (100, 1));
+
+        // An empty cell moved onto is stepped over without reading past the tape
+        debugger.restart();
+        debugger.set_head(200).unwrap();
+        assert_eq!(debugger.step(1, &mut input, &mut output).unwrap(), Pause::Stepped);
+        assert_eq!(debugger.machine.prg_head, 3);
+    }
+
+    #[test]
+    fn edits_past_limit_are_refused() {
+        let mut debugger = debugger("[-]");
+        assert_eq!(debugger.set_head(EDIT_LIMIT), Err(OutOfTape));
+        assert_eq!(debugger.set_cell(1 << 40, 1), Err(OutOfTape));
+        assert_eq!((debugger.machine.mem_ptr, debugger.machine.mem.len()), (0, 1));
+    }
+}
@@ -0,0 +1,127 @@
+use crate::command::{excerpt, SourcePos};
+use crate::command_opt::ParseError;
+use crate::limits::Interrupt;
+use std::fmt;
+use std::ops::Range;
+use std::path::PathBuf;
+
+/// Something a running program did that can't be carried out
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// The read/write head (or a cell being accessed) moved left of the start of the tape
+    PointerUnderflow,
+    /// A cell went above its maximum value or below 0 with overflow checks enabled
+    CellOverflow,
+    /// A cell's value can't be written in the chosen output encoding
+    InvalidChar,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Fault::PointerUnderflow => "Pointer underflow (attempted to move read/write head below 0)",
+            Fault::CellOverflow => "Cell overflow (value went above the maximum or below 0)",
+            Fault::InvalidChar => "Invalid char printed",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The source file couldn't be read
+    Open { path: PathBuf, source: std::io::Error },
+    /// A file the user asked for, like a report, couldn't be written
+    Write { path: PathBuf, source: std::io::Error },
+    /// The program has unmatched brackets
+    Parse(ParseError),
+    /// The program stopped with a fault
+    Runtime {
+        fault: Fault,
+        /// Index of the instruction that faulted
+        index: usize,
+        /// Source range the instruction was generated from
+        span: Range<usize>,
+        /// Position of the read/write head at the time
+        head: usize,
+    },
+    /// The program hit one of its `Limits`. It can be resumed from where it stopped.
+    Interrupted {
+        interrupt: Interrupt,
+        /// Index of the instruction the program will resume at
+        index: usize,
+        /// Source range the instruction was generated from
+        span: Range<usize>,
+        /// Position of the read/write head at the time
+        head: usize,
+    },
+    /// Reading the program's input failed
+    Input(std::io::Error),
+    /// Writing the program's output failed
+    Output(std::io::Error),
+    /// A backend failed to compile the program to native code
+    Compile(Box<dyn std::error::Error + Send + Sync>),
+    /// Talking to a debugger client failed
+    Connection(std::io::Error),
+}
+
+impl Error {
+    /// Process exit code to report this error with. Usage errors are reported by clap with 2.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::Parse(_) => 3,
+            Error::Runtime { .. } => 4,
+            Error::Open { .. } | Error::Write { .. } => 5,
+            Error::Input(_) | Error::Output(_) | Error::Connection(_) => 5,
+            Error::Compile(_) => 6,
+            Error::Interrupted { interrupt: Interrupt::OutOfFuel, .. } => 7,
+            Error::Interrupted { interrupt: Interrupt::Timeout, .. } => 8,
+        }
+    }
+
+    /// Describe the error for the user, pointing out where in the source `code` it happened
+    pub fn report(&self, code: &str) -> String {
+        let mut report = format!("Error: {}\n", self);
+        match self {
+            Error::Parse(err) => report += &err.report(code),
+            Error::Runtime { span, .. } | Error::Interrupted { span, .. } => {
+                let pos = SourcePos::find(code, span.start);
+                report += &format!("At line {}, column {}:\n", pos.line, pos.column);
+                report += &excerpt(code, span.clone());
+            }
+            _ => {}
+        }
+        report
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Open { path, source } => write!(f, "Can't open file '{}': {}", path.to_string_lossy(), source),
+            Error::Write { path, source } => write!(f, "Can't write file '{}': {}", path.to_string_lossy(), source),
+            Error::Parse(err) => write!(f, "Brackets not balanced ({} unmatched)", err.unmatched.len()),
+            Error::Runtime { fault, index, head, .. } => {
+                write!(f, "{} at instruction {} (read/write head at cell {})", fault, index, head)
+            }
+            Error::Interrupted { interrupt, index, head, .. } => {
+                write!(f, "{} at instruction {} (read/write head at cell {})", interrupt, index, head)
+            }
+            Error::Input(err) => write!(f, "Unable to read input: {}", err),
+            Error::Output(err) => write!(f, "Unable to write output: {}", err),
+            Error::Compile(err) => write!(f, "Failed to compile program: {}", err),
+            Error::Connection(err) => write!(f, "Lost connection to the debugger: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Open { source, .. } | Error::Write { source, .. } => Some(source),
+            Error::Input(err) | Error::Output(err) | Error::Connection(err) => Some(err),
+            Error::Parse(err) => Some(err),
+            Error::Compile(err) => Some(err.as_ref()),
+            Error::Runtime { .. } | Error::Interrupted { .. } => None,
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use std::io::{BufWriter, ErrorKind, Read, Stdin, Stdout, Write};
+
+/// How cell values are turned into bytes on the output stream
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum OutputEncoding {
+    /// Write the low byte of each cell as a single raw byte, so programs can emit binary data or
+    /// UTF-8 sequences byte-by-byte
+    #[default]
+    Raw,
+    /// Treat each cell as a Unicode code point and write it UTF-8 encoded
+    Utf8,
+    /// Treat each cell as a Latin-1 character and write it as a single byte. Unlike `Raw`, cells
+    /// wider than 8 bits holding values above 255 are an error instead of being truncated.
+    Latin1,
+}
+
+/// What `,` stores once the input stream is exhausted
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum EofBehavior {
+    /// Set the cell to 0
+    #[default]
+    Zero,
+    /// Set the cell to its maximum value (255 for 8-bit cells, i.e. -1)
+    Max,
+    /// Leave the cell as it was
+    Unchanged,
+}
+
+/// Input stream of a running program. Reads from any byte source: stdin, a file, a socket, or an
+/// in-memory buffer like `&[u8]`.
+pub struct Input<R = Stdin> {
+    reader: R,
+    eof: EofBehavior,
+    strict: bool,
+}
+
+impl Input {
+    /// Read from stdin. With `strict`, read errors are reported instead of being treated like the
+    /// end of input.
+    pub fn stdin(eof: EofBehavior, strict: bool) -> Self {
+        Self::new(std::io::stdin(), eof, strict)
+    }
+}
+
+impl<R: Read> Input<R> {
+    /// Read from `reader` one byte at a time, so it should be buffered if every read is costly.
+    /// With `strict`, read errors are reported instead of being treated like the end of input.
+    pub fn new(reader: R, eof: EofBehavior, strict: bool) -> Self {
+        Self { reader, eof, strict }
+    }
+
+    /// Erase the reader's type, for callers that can't be generic over it
+    pub fn boxed<'a>(self) -> Input<Box<dyn Read + 'a>>
+    where
+        R: 'a,
+    {
+        Input::new(Box::new(self.reader), self.eof, self.strict)
+    }
+
+    /// Read the next byte, or apply the EOF behavior if there is none. Returns the value to store
+    /// in the cell (truncated to the cell width), or `None` if the cell should be left unchanged.
+    pub fn get(&mut self) -> std::io::Result<Option<u64>> {
+        let mut buffer = [0u8; 1];
+        loop {
+            match self.reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(_) => return Ok(Some(u64::from(buffer[0]))),
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) if self.strict => return Err(err),
+                Err(_) => break,
+            }
+        }
+        Ok(match self.eof {
+            EofBehavior::Zero => Some(0),
+            EofBehavior::Max => Some(u64::MAX),
+            EofBehavior::Unchanged => None,
+        })
+    }
+}
+
+/// Output stream of a running program. Writes to any byte sink: stdout, a file, a socket, or an
+/// in-memory buffer like `Vec<u8>`.
+pub struct Output<W = BufWriter<Stdout>> {
+    writer: W,
+    encoding: OutputEncoding,
+}
+
+impl Output {
+    /// Write to stdout, buffered until the next `flush`
+    pub fn stdout(encoding: OutputEncoding) -> Self {
+        Self::new(BufWriter::new(std::io::stdout()), encoding)
+    }
+}
+
+impl<W: Write> Output<W> {
+    /// Write to `writer`. Values are written a character at a time, so wrap it in a `BufWriter`
+    /// if every write is costly.
+    pub fn new(writer: W, encoding: OutputEncoding) -> Self {
+        Self { writer, encoding }
+    }
+
+    /// Erase the writer's type, for callers that can't be generic over it
+    pub fn boxed<'a>(self) -> Output<Box<dyn Write + 'a>>
+    where
+        W: 'a,
+    {
+        Output::new(Box::new(self.writer), self.encoding)
+    }
+
+    /// Get at the writer, e.g. to take what a program wrote to a `Vec<u8>` so far
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    /// Get the writer back, e.g. to look at what a program wrote to a `Vec<u8>`
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// Write a cell's value. Values that don't map to a character in the chosen encoding fail
+    /// with `ErrorKind::InvalidData`.
+    pub fn put(&mut self, value: u64) -> std::io::Result<()> {
+        match self.encoding {
+            OutputEncoding::Raw => self.writer.write_all(&[value as u8]),
+            OutputEncoding::Utf8 => match u32::try_from(value).ok().and_then(char::from_u32) {
+                Some(ch) => {
+                    let mut buffer = [0u8; 4];
+                    self.writer.write_all(ch.encode_utf8(&mut buffer).as_bytes())
+                }
+                None => Err(ErrorKind::InvalidData.into()),
+            },
+            OutputEncoding::Latin1 => match u8::try_from(value) {
+                Ok(byte) => self.writer.write_all(&[byte]),
+                Err(_) => Err(ErrorKind::InvalidData.into()),
+            },
+        }
+    }
+
+    /// Write out everything buffered so far. Call this before blocking on input, so prompts show
+    /// up, and when the program ends.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reader that fails every read
+    struct Broken;
+
+    impl Read for Broken {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            Err(ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    fn read_all(input: &mut Input<impl Read>, count: usize) -> Vec<Option<u64>> {
+        (0..count).map(|_| input.get().unwrap()).collect()
+    }
+
+    fn write_all(encoding: OutputEncoding, values: &[u64]) -> std::io::Result<Vec<u8>> {
+        let mut output = Output::new(Vec::new(), encoding);
+        for &value in values {
+            output.put(value)?;
+        }
+        Ok(output.into_inner())
+    }
+
+    #[test]
+    fn eof_behaviors() {
+        let mut input = Input::new(&b"a"[..], EofBehavior::Zero, false);
+        assert_eq!(read_all(&mut input, 3), [Some(97), Some(0), Some(0)]);
+        let mut input = Input::new(&b"a"[..], EofBehavior::Max, false);
+        assert_eq!(read_all(&mut input, 2), [Some(97), Some(u64::MAX)]);
+        let mut input = Input::new(&b"a"[..], EofBehavior::Unchanged, false);
+        assert_eq!(read_all(&mut input, 2), [Some(97), None]);
+    }
+
+    #[test]
+    fn read_errors_end_input_unless_strict() {
+        let mut input = Input::new(Broken, EofBehavior::Max, false);
+        assert_eq!(input.get().unwrap(), Some(u64::MAX));
+        let mut input = Input::new(Broken, EofBehavior::Max, true);
+        assert_eq!(input.get().unwrap_err().kind(), ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn raw_writes_low_byte() {
+        assert_eq!(write_all(OutputEncoding::Raw, &[0x41, 0x142, 0xFF]).unwrap(), b"AB\xFF");
+    }
+
+    #[test]
+    fn utf8_encodes_code_points() {
+        let text = "A\u{e9}\u{20ac}\u{1f600}";
+        let values: Vec<u64> = text.chars().map(|ch| ch as u64).collect();
+        assert_eq!(write_all(OutputEncoding::Utf8, &values).unwrap(), text.as_bytes());
+        for invalid in [0xD800, 0x110000, u64::MAX] {
+            let err = write_all(OutputEncoding::Utf8, &[invalid]).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn latin1_rejects_wide_values() {
+        assert_eq!(write_all(OutputEncoding::Latin1, &[0x41, 0xE9]).unwrap(), b"A\xE9");
+        let err = write_all(OutputEncoding::Latin1, &[0x100]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,36 @@
+//! Front end and interpreter shared by the BrainFetch binaries: tokenizing BF source, optimizing
+//! it into `CommandOpt` instructions, and running those on a tape of 8 to 64-bit cells.
+//!
+//! `parse` turns source code into a `Program`, which `execute` runs with the cell type picked
+//! as a type parameter. To bound how long a program may run, use a `Machine` with `Limits`
+//! instead, which can also resume the program once it stopped. Backends that compile to native
+//! code can consume `Program::commands` directly and use `Program::spans` to point errors back
+//! at the source.
+//!
+//! `debug::Debugger` steps through a program command by command for interactive tools, with
+//! breakpoints on source positions and watchpoints on cells.
+//!
+//! `Machine::profile` runs a program while counting how often each instruction executes, and
+//! `profile::Profile` maps those counts back to loops and source positions. `coverage::Coverage`
+//! turns the counts of an unoptimized program into line and loop coverage in lcov format.
+//!
+//! Programs talk to the outside world through `io::Input` and `io::Output`, which wrap any
+//! `Read` and `Write`, so they can run against stdio as well as files, sockets or in-memory
+//! buffers.
+
+pub mod cell;
+pub mod command;
+pub mod command_opt;
+pub mod coverage;
+pub mod debug;
+pub mod error;
+pub mod io;
+pub mod limits;
+pub mod profile;
+
+pub use command_opt::{
+    execute, parse, parse_with, CommandOpt, Machine, OptLevel, OverflowMode, ParseError, Program,
+};
+pub use error::{Error, Fault};
+pub use limits::{Interrupt, Limits};
+pub use profile::Profile;
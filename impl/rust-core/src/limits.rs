@@ -0,0 +1,115 @@
+use std::time::Instant;
+
+/// Bounds on how long a program may run. A program that hits one stops with an
+/// `Error::Interrupted` saying which, and can be resumed with new limits.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    /// Number of instructions the program may execute
+    pub fuel: Option<u64>,
+    /// Point in time the program has to stop at
+    pub deadline: Option<Instant>,
+}
+
+/// Why a program was stopped before it finished
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interrupt {
+    OutOfFuel,
+    Timeout,
+}
+
+impl std::fmt::Display for Interrupt {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Interrupt::OutOfFuel => "Out of fuel",
+            Interrupt::Timeout => "Timed out",
+        })
+    }
+}
+
+/// Instructions executed between checks of the deadline. Reading the clock is slow compared to
+/// running an instruction, so this keeps the overhead low while still stopping a program within
+/// a millisecond or so of its deadline.
+const DEADLINE_INTERVAL: u64 = 1 << 16;
+
+/// Hands out a program's fuel in slices, checking the deadline every time a slice runs out. This
+/// lets the interpreter and compiled code get away with decrementing a counter as they go.
+#[derive(Debug)]
+pub struct Meter {
+    /// Fuel not handed out yet
+    fuel: Option<u64>,
+    deadline: Option<Instant>,
+}
+
+impl Meter {
+    pub fn new(limits: &Limits) -> Self {
+        Self {
+            fuel: limits.fuel,
+            deadline: limits.deadline,
+        }
+    }
+
+    /// Whether there are any limits to enforce at all
+    pub fn is_limited(&self) -> bool {
+        self.fuel.is_some() || self.deadline.is_some()
+    }
+
+    /// Number of instructions the program may execute before calling this again, or why it has
+    /// to stop now
+    pub fn refill(&mut self) -> Result<u64, Interrupt> {
+        let slice = match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => return Err(Interrupt::Timeout),
+            Some(_) => DEADLINE_INTERVAL,
+            None => u64::MAX,
+        };
+        match &mut self.fuel {
+            Some(0) => Err(Interrupt::OutOfFuel),
+            Some(fuel) => {
+                let slice = slice.min(*fuel);
+                *fuel -= slice;
+                Ok(slice)
+            }
+            None => Ok(slice),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::{EofBehavior, Input, Output, OutputEncoding};
+    use crate::{parse, Error, Machine, OverflowMode};
+
+    #[test]
+    fn program_out_of_fuel_resumes_with_new_limits() {
+        // Prints 1 to 5
+        let prg = parse("++++++[>++++++++<-]+++++[>+.<-]", 8, OverflowMode::Wrap).unwrap();
+        let mut input = Input::new(&[][..], EofBehavior::Zero, false);
+        let mut output = Output::new(Vec::new(), OutputEncoding::Raw);
+        let mut machine = Machine::<u8>::new();
+
+        // Enough fuel to get into the printing loop, but not through it
+        let limits = Limits { fuel: Some(12), deadline: None };
+        let result = machine.run(&prg, OverflowMode::Wrap, &limits, &mut input, &mut output);
+        let Err(Error::Interrupted { interrupt, index, head, .. }) = result else {
+            panic!("expected an interrupt, got {:?}", result);
+        };
+        assert_eq!(interrupt, Interrupt::OutOfFuel);
+        assert_eq!((index, head), (machine.prg_head, machine.mem_ptr));
+        let printed = output.get_mut().len();
+        assert!(printed > 0 && printed < 5);
+
+        machine.run(&prg, OverflowMode::Wrap, &Limits::default(), &mut input, &mut output).unwrap();
+        assert_eq!(output.into_inner(), b"12345");
+    }
+
+    #[test]
+    fn program_past_deadline_stops() {
+        let prg = parse("+[]", 8, OverflowMode::Wrap).unwrap();
+        let mut input = Input::new(&[][..], EofBehavior::Zero, false);
+        let mut output = Output::new(Vec::new(), OutputEncoding::Raw);
+        let limits = Limits { fuel: None, deadline: Some(Instant::now()) };
+        let mut machine = Machine::<u8>::new();
+        let result = machine.run(&prg, OverflowMode::Wrap, &limits, &mut input, &mut output);
+        assert!(matches!(result, Err(Error::Interrupted { interrupt: Interrupt::Timeout, .. })));
+    }
+}
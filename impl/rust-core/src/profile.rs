@@ -0,0 +1,213 @@
+use crate::command::{Command, SourcePos};
+use crate::command_opt::{CommandOpt, Program};
+use std::fmt::Write;
+use std::ops::Range;
+
+/// Longest stretch of a loop's source quoted in a report, in commands
+const QUOTE_LEN: usize = 60;
+
+/// How often each instruction of a program was executed, as counted by `Machine::profile`
+#[derive(Clone, Debug)]
+pub struct Profile {
+    /// Executions of each instruction, by index
+    pub counts: Vec<u64>,
+}
+
+/// Counts for one loop that's still a loop after optimization, i.e. one `OpenBr` and its
+/// `CloseBr`
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoopStats {
+    /// Index of the `OpenBr`
+    pub open: usize,
+    /// Index of the `CloseBr`
+    pub close: usize,
+    /// Number of times the loop was reached, whether or not its body ran
+    pub entries: u64,
+    /// Number of times the body ran
+    pub iterations: u64,
+    /// Instructions executed from the `OpenBr` to the `CloseBr`, including nested loops
+    pub executed: u64,
+    /// Instructions executed in the loop itself, leaving out nested loops
+    pub own: u64,
+    /// Source range from the `[` to the `]`
+    pub span: Range<usize>,
+}
+
+impl Profile {
+    /// Empty counts for `prg`
+    pub fn new(prg: &Program) -> Self {
+        Self { counts: vec![0; prg.commands.len()] }
+    }
+
+    /// Total number of instructions executed
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Counts for every loop of `prg`, in program order. A loop is entered through its
+    /// `OpenBr` and every pass through its body ends at its `CloseBr`, so those two counts
+    /// give the entries and iterations.
+    pub fn loops(&self, prg: &Program) -> Vec<LoopStats> {
+        // executed[i] is the number of instructions executed before index i
+        let executed: Vec<u64> = std::iter::once(0)
+            .chain(self.counts.iter().scan(0, |sum, &count| {
+                *sum += count;
+                Some(*sum)
+            }))
+            .collect();
+        let mut loops: Vec<LoopStats> = prg
+            .commands
+            .iter()
+            .enumerate()
+            .filter_map(|(open, cmd)| match *cmd {
+                CommandOpt::OpenBr(close) => {
+                    let inside = executed[close + 1] - executed[open];
+                    Some(LoopStats {
+                        open,
+                        close,
+                        entries: self.counts[open],
+                        iterations: self.counts[close],
+                        executed: inside,
+                        own: inside,
+                        span: prg.spans[open].start..prg.spans[close].end,
+                    })
+                }
+                _ => None,
+            })
+            .collect();
+
+        // Loops come in program order, so each one's parent is the nearest earlier loop that
+        // hasn't closed yet
+        let mut open_loops: Vec<usize> = Vec::new();
+        for i in 0..loops.len() {
+            while let Some(&parent) = open_loops.last() {
+                if loops[parent].close > loops[i].open {
+                    loops[parent].own -= loops[i].executed;
+                    break;
+                }
+                open_loops.pop();
+            }
+            open_loops.push(i);
+        }
+        loops
+    }
+
+    /// Summarize the profile of `prg`, generated from `code`: how often each kind of
+    /// instruction ran, then the `top` loops and instructions that ran the most, with where
+    /// they are in the source.
+    pub fn report(&self, prg: &Program, code: &str, top: usize) -> String {
+        let total = self.total();
+        let percent = |count: u64| 100.0 * count as f64 / total.max(1) as f64;
+        let mut report = String::new();
+        writeln!(report, "Executed {} instructions", total).unwrap();
+
+        let mut kinds: Vec<(&str, u64)> = Vec::new();
+        for (cmd, &count) in prg.commands.iter().zip(&self.counts) {
+            match kinds.iter_mut().find(|(kind, _)| *kind == kind_name(cmd)) {
+                Some((_, sum)) => *sum += count,
+                None => kinds.push((kind_name(cmd), count)),
+            }
+        }
+        kinds.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+        writeln!(report, "\nInstructions by kind:").unwrap();
+        for (kind, count) in kinds.iter().filter(|&&(_, count)| count > 0) {
+            writeln!(report, "  {:<12} {:>6.2}% {:>14}", kind, percent(*count), count).unwrap();
+        }
+
+        let mut loops = self.loops(prg);
+        loops.sort_by_key(|stats| std::cmp::Reverse(stats.own));
+        writeln!(report, "\nHottest loops (share of instructions, then including nested loops):")
+            .unwrap();
+        if loops.first().is_none_or(|stats| stats.own == 0) {
+            writeln!(report, "  None ran").unwrap();
+        }
+        for stats in loops.iter().take(top).filter(|stats| stats.own > 0) {
+            let pos = SourcePos::find(code, stats.span.start);
+            writeln!(
+                report,
+                "  {:>6.2}% {:>6.2}%  line {}, column {}: {} iterations over {} entries",
+                percent(stats.own),
+                percent(stats.executed),
+                pos.line,
+                pos.column,
+                stats.iterations,
+                stats.entries
+            )
+            .unwrap();
+            writeln!(report, "                   {}", quote(&code[stats.span.clone()])).unwrap();
+        }
+
+        let mut hottest: Vec<usize> = (0..self.counts.len()).collect();
+        hottest.sort_by_key(|&index| std::cmp::Reverse(self.counts[index]));
+        writeln!(report, "\nHottest instructions:").unwrap();
+        for index in hottest.into_iter().take(top).filter(|&index| self.counts[index] > 0) {
+            let pos = SourcePos::find(code, prg.spans[index].start);
+            writeln!(
+                report,
+                "  {:>6.2}%  line {}, column {}: {:?} ({} times)",
+                percent(self.counts[index]),
+                pos.line,
+                pos.column,
+                prg.commands[index],
+                self.counts[index]
+            )
+            .unwrap();
+            writeln!(report, "           {}", quote(&code[prg.spans[index].clone()])).unwrap();
+        }
+        report
+    }
+
+    /// Profile of `prg`, generated from `code`, in the folded stack format that flamegraph.pl
+    /// and inferno render: one line per loop, listing the loops around it from the outermost
+    /// in, labelled by where their `[` is, and then the number of instructions executed in
+    /// the loop itself. Instructions outside any loop are counted under `root` alone.
+    pub fn folded(&self, prg: &Program, code: &str, root: &str) -> String {
+        let mut folded = String::new();
+        let mut outside = self.total();
+        // Loops that haven't closed yet, with their frames
+        let mut stack: Vec<(usize, String)> = vec![(usize::MAX, String::from(root))];
+        for stats in self.loops(prg) {
+            while stack.last().is_some_and(|&(close, _)| close < stats.open) {
+                stack.pop();
+            }
+            if stack.len() == 1 {
+                outside -= stats.executed;
+            }
+            let pos = SourcePos::find(code, stats.span.start);
+            stack.push((stats.close, format!("loop@{}:{}", pos.line, pos.column)));
+            if stats.own > 0 {
+                let frames: Vec<&str> = stack.iter().map(|(_, frame)| frame.as_str()).collect();
+                writeln!(folded, "{} {}", frames.join(";"), stats.own).unwrap();
+            }
+        }
+        if outside > 0 {
+            writeln!(folded, "{} {}", root, outside).unwrap();
+        }
+        folded
+    }
+}
+
+fn kind_name(cmd: &CommandOpt) -> &'static str {
+    match cmd {
+        CommandOpt::ChPtr(_) => "ChPtr",
+        CommandOpt::ChVal { .. } => "ChVal",
+        CommandOpt::PutChar { .. } => "PutChar",
+        CommandOpt::GetChar { .. } => "GetChar",
+        CommandOpt::Zero { .. } => "Zero",
+        CommandOpt::MulAdd { .. } => "MulAdd",
+        CommandOpt::Scan(_) => "Scan",
+        CommandOpt::LoopForever => "LoopForever",
+        CommandOpt::OpenBr(_) => "OpenBr",
+        CommandOpt::CloseBr(_) => "CloseBr",
+    }
+}
+
+/// The commands in `source`, without comments and cut short if there are many of them
+fn quote(source: &str) -> String {
+    let mut commands = source.chars().filter(|&ch| Command::from_char(ch).is_some());
+    let mut quoted: String = commands.by_ref().take(QUOTE_LEN).collect();
+    if commands.next().is_some() {
+        quoted.push_str("...");
+    }
+    quoted
+}
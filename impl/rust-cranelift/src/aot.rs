@@ -0,0 +1,588 @@
+use brainfetch_core::command::SourcePos;
+use brainfetch_core::command_opt::{self, Program};
+use brainfetch_core::io::{EofBehavior, OutputEncoding};
+use brainfetch_core::{Error, Fault};
+use clap::{Args, ValueEnum};
+use crate::jit::{self, JitOptions, STATUS_INPUT_ERROR, STATUS_INVALID_CHAR, STATUS_OK, STATUS_OUTPUT_ERROR, STATUS_OVERFLOW, STATUS_UNDERFLOW};
+use crate::runtime::Runtime;
+use cranelift::frontend::Switch;
+use cranelift::prelude::*;
+use cranelift_module::{DataDescription, DataId, FuncId, Linkage, Module, ModuleResult};
+use cranelift_object::{ObjectBuilder, ObjectModule};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::mem::offset_of;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+#[derive(Args)]
+pub struct CompileArgs {
+    /// BrainF*** file to compile
+    pub file: PathBuf,
+
+    /// What to compile the program to
+    #[arg(long, value_enum, default_value_t)]
+    emit: Emit,
+
+    /// File to write, by default the source file without its extension for executables, or
+    /// with `.o` for object files
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<PathBuf>,
+
+    /// Number of cells the tape starts out with, which is all there is with `--unchecked`
+    #[arg(long, value_name = "CELLS", default_value_t = 30_000, value_parser = clap::value_parser!(u64).range(1..))]
+    tape_size: u64,
+
+    /// Program that links the object file into an executable, called like a C compiler
+    #[arg(long, value_name = "PROGRAM", default_value = "cc")]
+    linker: String,
+
+    #[command(flatten)]
+    options: crate::Options,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, ValueEnum)]
+enum Emit {
+    /// Executable for the host, linked against the C library
+    #[default]
+    Exe,
+    /// Relocatable object file defining `main`, to link yourself
+    Obj,
+}
+
+/// Compile the program in `code` as `args` ask for, and write it out
+pub fn run(args: &CompileArgs, code: &str) -> Result<(), Error> {
+    let options = &args.options;
+    let program = command_opt::parse(code, options.cell_bits, options.overflow)
+        .map_err(Error::Parse)?;
+    let object = compile_object(&program, code, args)
+        .map_err(Error::Compile)?;
+
+    let output = match (&args.output, args.emit) {
+        (Some(output), _) => output.clone(),
+        (None, Emit::Exe) => args.file.with_extension(""),
+        (None, Emit::Obj) => args.file.with_extension("o"),
+    };
+    if args.emit == Emit::Obj {
+        return std::fs::write(&output, object).map_err(|source| Error::Write { path: output, source });
+    }
+    // Next to the output, as a new file, so nothing already there is written through
+    let name = output.file_name().unwrap_or_default().to_string_lossy();
+    let object_path = output.with_file_name(format!(".{}.{}.o", name, std::process::id()));
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&object_path)
+        .map_err(|source| Error::Write { path: object_path.clone(), source })?;
+    let linked = file
+        .write_all(&object)
+        .map_err(|source| Error::Write { path: object_path.clone(), source })
+        .and_then(|()| {
+            drop(file);
+            link(&args.linker, &object_path, &output)
+        });
+    let _ = std::fs::remove_file(&object_path);
+    linked
+}
+
+/// Call `linker` like a C compiler to link `object` with the C library into `output`
+fn link(linker: &str, object: &Path, output: &Path) -> Result<(), Error> {
+    let status = Command::new(linker)
+        .arg(object)
+        .arg("-o")
+        .arg(output)
+        .status()
+        .map_err(|err| Error::Compile(format!("Can't run linker '{}': {}", linker, err).into()))?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(Error::Compile(format!("Linker '{}' failed ({})", linker, status).into())),
+    }
+}
+
+/// Name of the C library's `FILE *` for standard input
+const STDIN_SYMBOL: &str = if cfg!(target_os = "macos") { "__stdinp" } else { "stdin" };
+
+/// C library functions the runtime calls, none of them variadic since Cranelift can't call those
+struct Libc {
+    putchar: FuncId,
+    getchar: FuncId,
+    fflush: FuncId,
+    ferror: FuncId,
+    calloc: FuncId,
+    realloc: FuncId,
+    memset: FuncId,
+    write: FuncId,
+    abort: FuncId,
+    stdin: DataId,
+}
+
+impl Libc {
+    #[allow(clippy::result_large_err)]
+    fn declare(module: &mut ObjectModule) -> ModuleResult<Self> {
+        let ptr = module.target_config().pointer_type();
+        let mut declare = |name: &str, params: &[Type], returns: &[Type]| {
+            let mut sig = module.make_signature();
+            sig.params.extend(params.iter().map(|&ty| AbiParam::new(ty)));
+            sig.returns.extend(returns.iter().map(|&ty| AbiParam::new(ty)));
+            module.declare_function(name, Linkage::Import, &sig)
+        };
+        Ok(Self {
+            putchar: declare("putchar", &[types::I32], &[types::I32])?,
+            getchar: declare("getchar", &[], &[types::I32])?,
+            fflush: declare("fflush", &[ptr], &[types::I32])?,
+            ferror: declare("ferror", &[ptr], &[types::I32])?,
+            calloc: declare("calloc", &[ptr, ptr], &[ptr])?,
+            realloc: declare("realloc", &[ptr, ptr], &[ptr])?,
+            memset: declare("memset", &[ptr, types::I32, ptr], &[ptr])?,
+            write: declare("write", &[types::I32, ptr, ptr], &[ptr])?,
+            abort: declare("abort", &[], &[])?,
+            stdin: module.declare_data(STDIN_SYMBOL, Linkage::Import, false, false)?,
+        })
+    }
+}
+
+/// Compile `program`, generated from `code`, to a relocatable object for the host. Along with
+/// the program itself, it defines the host functions the compiled code calls back into, and a
+/// `main` that sets up the tape, runs the program and reports faults like the interpreter does.
+fn compile_object(
+    program: &Program,
+    code: &str,
+    args: &CompileArgs,
+) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    let options = &args.options;
+    let mut flags = settings::builder();
+    flags.set("opt_level", "speed")?;
+    // Executables are position independent by default on most systems
+    flags.set("is_pic", "true")?;
+    let isa = cranelift_native::builder()?.finish(settings::Flags::new(flags))?;
+    let builder = ObjectBuilder::new(isa, "brainfetch", cranelift_module::default_libcall_names())?;
+    let mut module = ObjectModule::new(builder);
+
+    let jit_options = JitOptions {
+        cell_bits: options.cell_bits,
+        overflow: options.overflow,
+        bounds_checks: !options.unchecked,
+        metered: false,
+    };
+    let (execute, _, _) = jit::define_program(&mut module, &program.commands, &jit_options)?;
+    let cell_type = Type::int(options.cell_bits as u16).expect("cell width must be 8, 16, 32 or 64 bits");
+    let libc = Libc::declare(&mut module)?;
+    define_put_char(&mut module, &libc, options.output_encoding)?;
+    define_get_char(&mut module, &libc, cell_type, options.eof, options.strict_input)?;
+    define_grow_tape(&mut module, &libc, cell_type)?;
+    let report = define_report(&mut module, &libc, program, code)?;
+    define_main(&mut module, &libc, execute, report, cell_type, args.tape_size)?;
+
+    Ok(module.finish().emit()?)
+}
+
+/// Define the function `name` with the parameters and results given by their types. `build`
+/// gets the builder positioned in the entry block and the function's parameters, and has to end
+/// every block it creates.
+#[allow(clippy::result_large_err)]
+fn define(
+    module: &mut ObjectModule,
+    name: &str,
+    linkage: Linkage,
+    (params, returns): (&[Type], &[Type]),
+    build: impl FnOnce(&mut ObjectModule, &mut FunctionBuilder, &[Value]),
+) -> ModuleResult<FuncId> {
+    let mut ctx = module.make_context();
+    ctx.func.signature.params.extend(params.iter().map(|&ty| AbiParam::new(ty)));
+    ctx.func.signature.returns.extend(returns.iter().map(|&ty| AbiParam::new(ty)));
+    let func_id = module.declare_function(name, linkage, &ctx.func.signature)?;
+
+    let mut func_ctx = FunctionBuilderContext::new();
+    let mut builder = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+    let entry_block = builder.create_block();
+    builder.append_block_params_for_function_params(entry_block);
+    builder.switch_to_block(entry_block);
+    let params = builder.block_params(entry_block).to_vec();
+    build(module, &mut builder, &params);
+    builder.seal_all_blocks();
+    builder.finalize();
+
+    module.define_function(func_id, &mut ctx)?;
+    module.clear_context(&mut ctx);
+    Ok(func_id)
+}
+
+/// Define read-only data holding `bytes`, and return its address in the function being built
+fn data_addr(module: &mut ObjectModule, builder: &mut FunctionBuilder, name: &str, bytes: Vec<u8>) -> Value {
+    let ptr = module.target_config().pointer_type();
+    let data_id = module.declare_data(name, Linkage::Local, false, false).expect("data names are unique");
+    let mut data = DataDescription::new();
+    data.define(bytes.into_boxed_slice());
+    module.define_data(data_id, &data).expect("data is only defined once");
+    let global = module.declare_data_in_func(data_id, builder.func);
+    builder.ins().global_value(ptr, global)
+}
+
+/// Call `func` and return its results
+fn call(module: &mut ObjectModule, builder: &mut FunctionBuilder, func: FuncId, args: &[Value]) -> Vec<Value> {
+    let func_ref = module.declare_func_in_func(func, builder.func);
+    let call = builder.ins().call(func_ref, args);
+    builder.inst_results(call).to_vec()
+}
+
+/// Return `status` if `condition` is set, and continue in a new block otherwise
+fn return_if(builder: &mut FunctionBuilder, condition: Value, status: u8) {
+    let fail_block = builder.create_block();
+    let cont_block = builder.create_block();
+    builder.ins().brif(condition, fail_block, &[], cont_block, &[]);
+    builder.switch_to_block(fail_block);
+    let status = builder.ins().iconst(types::I8, i64::from(status));
+    builder.ins().return_(&[status]);
+    builder.switch_to_block(cont_block);
+}
+
+/// `put_char(runtime, value) -> status`: write a cell's value to stdout in `encoding`
+#[allow(clippy::result_large_err)]
+fn define_put_char(module: &mut ObjectModule, libc: &Libc, encoding: OutputEncoding) -> ModuleResult<FuncId> {
+    let ptr = module.target_config().pointer_type();
+    let sig = (&[ptr, types::I64][..], &[types::I8][..]);
+    define(module, "put_char", Linkage::Local, sig, |module, builder, params| {
+        let value = params[1];
+        let mut put_byte = |builder: &mut FunctionBuilder, byte: Value| {
+            let byte = builder.ins().ireduce(types::I32, byte);
+            let result = call(module, builder, libc.putchar, &[byte])[0];
+            let failed = builder.ins().icmp_imm(IntCC::Equal, result, -1);
+            return_if(builder, failed, STATUS_OUTPUT_ERROR);
+        };
+        match encoding {
+            OutputEncoding::Raw => {
+                let byte = builder.ins().band_imm(value, 0xFF);
+                put_byte(builder, byte);
+            }
+            OutputEncoding::Latin1 => {
+                let invalid = builder.ins().icmp_imm(IntCC::UnsignedGreaterThan, value, 0xFF);
+                return_if(builder, invalid, STATUS_INVALID_CHAR);
+                put_byte(builder, value);
+            }
+            OutputEncoding::Utf8 => {
+                let too_large = builder.ins().icmp_imm(IntCC::UnsignedGreaterThan, value, 0x10FFFF);
+                let surrogate_offset = builder.ins().iadd_imm(value, -0xD800);
+                let surrogate = builder.ins().icmp_imm(IntCC::UnsignedLessThan, surrogate_offset, 0x800);
+                let invalid = builder.ins().bor(too_large, surrogate);
+                return_if(builder, invalid, STATUS_INVALID_CHAR);
+
+                // One block per encoded length, picked by the first limit the value is below
+                let done_block = builder.create_block();
+                for (len, limit, lead) in [(1, 0x80, 0x00), (2, 0x800, 0xC0), (3, 0x10000, 0xE0), (4, 0, 0xF0)] {
+                    let encode_block = builder.create_block();
+                    if len < 4 {
+                        let next_block = builder.create_block();
+                        let below = builder.ins().icmp_imm(IntCC::UnsignedLessThan, value, limit);
+                        builder.ins().brif(below, encode_block, &[], next_block, &[]);
+                        builder.switch_to_block(encode_block);
+                        encode_utf8(builder, &mut put_byte, value, len, lead);
+                        builder.ins().jump(done_block, &[]);
+                        builder.switch_to_block(next_block);
+                    } else {
+                        encode_utf8(builder, &mut put_byte, value, len, lead);
+                        builder.ins().jump(done_block, &[]);
+                    }
+                }
+                builder.switch_to_block(done_block);
+            }
+        }
+        let ok = builder.ins().iconst(types::I8, i64::from(STATUS_OK));
+        builder.ins().return_(&[ok]);
+    })
+}
+
+/// Write the `len` bytes of `value`'s UTF-8 encoding, the first one marked with `lead`
+fn encode_utf8(
+    builder: &mut FunctionBuilder,
+    put_byte: &mut impl FnMut(&mut FunctionBuilder, Value),
+    value: Value,
+    len: i64,
+    lead: i64,
+) {
+    let first = builder.ins().ushr_imm(value, 6 * (len - 1));
+    let first = builder.ins().bor_imm(first, lead);
+    put_byte(builder, first);
+    for continuation in (0..len - 1).rev() {
+        let byte = builder.ins().ushr_imm(value, 6 * continuation);
+        let byte = builder.ins().band_imm(byte, 0x3F);
+        let byte = builder.ins().bor_imm(byte, 0x80);
+        put_byte(builder, byte);
+    }
+}
+
+/// `get_char(runtime, cell) -> status`: flush stdout, then read a byte from stdin into `cell`,
+/// or apply the EOF behavior. Read errors count as the end of input unless `strict`.
+#[allow(clippy::result_large_err)]
+fn define_get_char(
+    module: &mut ObjectModule,
+    libc: &Libc,
+    cell_type: Type,
+    eof: EofBehavior,
+    strict: bool,
+) -> ModuleResult<FuncId> {
+    let ptr = module.target_config().pointer_type();
+    let sig = (&[ptr, ptr][..], &[types::I8][..]);
+    define(module, "get_char", Linkage::Local, sig, |module, builder, params| {
+        let cell = params[1];
+        // Make sure any prompt is visible before blocking on input
+        let all_streams = builder.ins().iconst(ptr, 0);
+        let flushed = call(module, builder, libc.fflush, &[all_streams])[0];
+        return_if(builder, flushed, STATUS_OUTPUT_ERROR);
+
+        let ch = call(module, builder, libc.getchar, &[])[0];
+        let at_eof = builder.ins().icmp_imm(IntCC::Equal, ch, -1);
+        let eof_block = builder.create_block();
+        let store_block = builder.create_block();
+        let done_block = builder.create_block();
+        builder.ins().brif(at_eof, eof_block, &[], store_block, &[]);
+
+        builder.switch_to_block(eof_block);
+        if strict {
+            // getchar returns EOF for read errors too, which only the stream's error flag tells
+            let stdin = module.declare_data_in_func(libc.stdin, builder.func);
+            let stdin = builder.ins().global_value(ptr, stdin);
+            let stdin = builder.ins().load(ptr, MemFlags::trusted(), stdin, 0);
+            let failed = call(module, builder, libc.ferror, &[stdin])[0];
+            return_if(builder, failed, STATUS_INPUT_ERROR);
+        }
+        let value = match eof {
+            EofBehavior::Zero => Some(builder.ins().iconst(cell_type, 0)),
+            EofBehavior::Max => Some(builder.ins().iconst(cell_type, -1)),
+            EofBehavior::Unchanged => None,
+        };
+        if let Some(value) = value {
+            builder.ins().store(MemFlags::trusted(), value, cell, 0);
+        }
+        builder.ins().jump(done_block, &[]);
+
+        builder.switch_to_block(store_block);
+        let value = match cell_type.bits() {
+            8 | 16 => builder.ins().ireduce(cell_type, ch),
+            32 => ch,
+            _ => builder.ins().uextend(cell_type, ch),
+        };
+        builder.ins().store(MemFlags::trusted(), value, cell, 0);
+        builder.ins().jump(done_block, &[]);
+
+        builder.switch_to_block(done_block);
+        let ok = builder.ins().iconst(types::I8, i64::from(STATUS_OK));
+        builder.ins().return_(&[ok]);
+    })
+}
+
+/// `grow_tape(runtime, index)`: reallocate the tape so `index` is in bounds, like
+/// `runtime::grow_tape`, zeroing the new cells
+#[allow(clippy::result_large_err)]
+fn define_grow_tape(module: &mut ObjectModule, libc: &Libc, cell_type: Type) -> ModuleResult<FuncId> {
+    let ptr = module.target_config().pointer_type();
+    let cell_shift = i64::from(cell_type.bytes().ilog2());
+    define(module, "grow_tape", Linkage::Local, (&[ptr, ptr], &[]), |module, builder, params| {
+        let (runtime, index) = (params[0], params[1]);
+        let cells = builder.ins().load(ptr, MemFlags::trusted(), runtime, offset_of!(Runtime, cells) as i32);
+        let len = builder.ins().load(ptr, MemFlags::trusted(), runtime, offset_of!(Runtime, len) as i32);
+        let needed = builder.ins().iadd_imm(index, 1);
+        let doubled = builder.ins().ishl_imm(len, 1);
+        let new_len = builder.ins().umax(needed, doubled);
+
+        let size = builder.ins().ishl_imm(new_len, cell_shift);
+        let new_cells = call(module, builder, libc.realloc, &[cells, size])[0];
+        let failed = builder.ins().icmp_imm(IntCC::Equal, new_cells, 0);
+        let abort_block = builder.create_block();
+        let cont_block = builder.create_block();
+        builder.ins().brif(failed, abort_block, &[], cont_block, &[]);
+        builder.switch_to_block(abort_block);
+        call(module, builder, libc.abort, &[]);
+        builder.ins().trap(TrapCode::unwrap_user(1));
+
+        builder.switch_to_block(cont_block);
+        let old_size = builder.ins().ishl_imm(len, cell_shift);
+        let added = builder.ins().isub(size, old_size);
+        let new_part = builder.ins().iadd(new_cells, old_size);
+        let zero = builder.ins().iconst(types::I32, 0);
+        call(module, builder, libc.memset, &[new_part, zero, added]);
+        builder.ins().store(MemFlags::trusted(), new_cells, runtime, offset_of!(Runtime, cells) as i32);
+        builder.ins().store(MemFlags::trusted(), new_len, runtime, offset_of!(Runtime, len) as i32);
+        builder.ins().return_(&[]);
+    })
+}
+
+/// `report(message, message_len, index, head)`: write a fault to stderr the way
+/// `Error::report` does, with the position of the instruction at `index` in `code`
+#[allow(clippy::result_large_err)]
+fn define_report(module: &mut ObjectModule, libc: &Libc, program: &Program, code: &str) -> ModuleResult<FuncId> {
+    let ptr = module.target_config().pointer_type();
+    let write_number = define_write_number(module, libc)?;
+    // Line and column of each instruction, as pairs of 32-bit numbers
+    let mut positions = Vec::new();
+    for span in &program.spans {
+        let pos = SourcePos::find(code, span.start);
+        positions.extend_from_slice(&(pos.line as u32).to_ne_bytes());
+        positions.extend_from_slice(&(pos.column as u32).to_ne_bytes());
+    }
+    let sig = (&[ptr, ptr, ptr, ptr][..], &[][..]);
+    define(module, "report", Linkage::Local, sig, |module, builder, params| {
+        let (message, message_len, index, head) = (params[0], params[1], params[2], params[3]);
+        let stderr = builder.ins().iconst(types::I32, 2);
+        let write_str = |module: &mut ObjectModule, builder: &mut FunctionBuilder, name: &str, text: &str| {
+            let addr = data_addr(module, builder, name, text.as_bytes().to_vec());
+            let len = builder.ins().iconst(ptr, text.len() as i64);
+            call(module, builder, libc.write, &[stderr, addr, len]);
+        };
+        write_str(module, builder, "error_prefix", "Error: ");
+        call(module, builder, libc.write, &[stderr, message, message_len]);
+        write_str(module, builder, "error_index", " at instruction ");
+        call(module, builder, write_number, &[index]);
+        write_str(module, builder, "error_head", " (read/write head at cell ");
+        call(module, builder, write_number, &[head]);
+        write_str(module, builder, "error_line", ")\nAt line ");
+        let table = data_addr(module, builder, "positions", positions);
+        let offset = builder.ins().imul_imm(index, 8);
+        let position = builder.ins().iadd(table, offset);
+        let line = builder.ins().uload32(MemFlags::trusted(), position, 0);
+        let column = builder.ins().uload32(MemFlags::trusted(), position, 4);
+        let (line, column) = if ptr == types::I32 {
+            (builder.ins().ireduce(types::I32, line), builder.ins().ireduce(types::I32, column))
+        } else {
+            (line, column)
+        };
+        call(module, builder, write_number, &[line]);
+        write_str(module, builder, "error_column", ", column ");
+        call(module, builder, write_number, &[column]);
+        write_str(module, builder, "error_end", "\n");
+        builder.ins().return_(&[]);
+    })
+}
+
+/// `write_number(value)`: write an unsigned number to stderr in decimal
+#[allow(clippy::result_large_err)]
+fn define_write_number(module: &mut ObjectModule, libc: &Libc) -> ModuleResult<FuncId> {
+    let ptr = module.target_config().pointer_type();
+    // Enough digits for any 64-bit number
+    const DIGITS: u32 = 20;
+    define(module, "write_number", Linkage::Local, (&[ptr], &[]), |module, builder, params| {
+        let slot = builder.create_sized_stack_slot(StackSlotData::new(StackSlotKind::ExplicitSlot, DIGITS, 0));
+        let buffer = builder.ins().stack_addr(ptr, slot, 0);
+        let end = builder.ins().iadd_imm(buffer, i64::from(DIGITS));
+
+        // Fill the buffer from the end, one digit at a time
+        let digit_block = builder.create_block();
+        let value = builder.append_block_param(digit_block, ptr);
+        let pos = builder.append_block_param(digit_block, ptr);
+        let write_block = builder.create_block();
+        builder.ins().jump(digit_block, &[params[0], end]);
+
+        builder.switch_to_block(digit_block);
+        let pos = builder.ins().iadd_imm(pos, -1);
+        let digit = builder.ins().urem_imm(value, 10);
+        let digit = builder.ins().iadd_imm(digit, i64::from(b'0'));
+        let digit = builder.ins().ireduce(types::I8, digit);
+        builder.ins().store(MemFlags::trusted(), digit, pos, 0);
+        let rest = builder.ins().udiv_imm(value, 10);
+        builder.ins().brif(rest, digit_block, &[rest, pos], write_block, &[]);
+
+        builder.switch_to_block(write_block);
+        let stderr = builder.ins().iconst(types::I32, 2);
+        let len = builder.ins().isub(end, pos);
+        call(module, builder, libc.write, &[stderr, pos, len]);
+        builder.ins().return_(&[]);
+    })
+}
+
+/// `main`: set up a `Runtime` with a tape of `tape_size` cells, call `execute` on it, and turn
+/// the status it returns into the interpreter's exit codes
+#[allow(clippy::result_large_err)]
+fn define_main(
+    module: &mut ObjectModule,
+    libc: &Libc,
+    execute: FuncId,
+    report: FuncId,
+    cell_type: Type,
+    tape_size: u64,
+) -> ModuleResult<FuncId> {
+    let ptr = module.target_config().pointer_type();
+    let sig = (&[types::I32, ptr][..], &[types::I32][..]);
+    define(module, "main", Linkage::Export, sig, |module, builder, _| {
+        // Only the fields the compiled code uses are set, so the rest of the struct stays unused
+        let size = std::mem::size_of::<Runtime>() as u32;
+        let slot = builder.create_sized_stack_slot(StackSlotData::new(StackSlotKind::ExplicitSlot, size, 3));
+        let runtime = builder.ins().stack_addr(ptr, slot, 0);
+
+        let len = builder.ins().iconst(ptr, tape_size as i64);
+        let cell_bytes = builder.ins().iconst(ptr, i64::from(cell_type.bytes()));
+        let cells = call(module, builder, libc.calloc, &[len, cell_bytes])[0];
+        let failed = builder.ins().icmp_imm(IntCC::Equal, cells, 0);
+        let abort_block = builder.create_block();
+        let run_block = builder.create_block();
+        builder.ins().brif(failed, abort_block, &[], run_block, &[]);
+        builder.switch_to_block(abort_block);
+        call(module, builder, libc.abort, &[]);
+        builder.ins().trap(TrapCode::unwrap_user(1));
+
+        builder.switch_to_block(run_block);
+        let zero = builder.ins().iconst(ptr, 0);
+        builder.ins().store(MemFlags::trusted(), cells, runtime, offset_of!(Runtime, cells) as i32);
+        builder.ins().store(MemFlags::trusted(), len, runtime, offset_of!(Runtime, len) as i32);
+        builder.ins().store(MemFlags::trusted(), zero, runtime, offset_of!(Runtime, head) as i32);
+        builder.ins().store(MemFlags::trusted(), zero, runtime, offset_of!(Runtime, error_at) as i32);
+        let status = call(module, builder, execute, &[runtime])[0];
+        // Flush before reporting anything, so the program's output comes first
+        let flushed = call(module, builder, libc.fflush, &[zero])[0];
+
+        let exit = |builder: &mut FunctionBuilder, code: i64| {
+            let code = builder.ins().iconst(types::I32, code);
+            builder.ins().return_(&[code]);
+        };
+        // Faults, or the message for an I/O error
+        let mut blocks = Vec::new();
+        for (status, fault) in [
+            (STATUS_UNDERFLOW, Fault::PointerUnderflow),
+            (STATUS_INVALID_CHAR, Fault::InvalidChar),
+            (STATUS_OVERFLOW, Fault::CellOverflow),
+        ] {
+            blocks.push((status, builder.create_block(), Ok(fault)));
+        }
+        let output_error_block = builder.create_block();
+        blocks.push((STATUS_OUTPUT_ERROR, output_error_block, Err("Unable to write output")));
+        blocks.push((STATUS_INPUT_ERROR, builder.create_block(), Err("Unable to read input")));
+
+        let ok_block = builder.create_block();
+        let mut switch = Switch::new();
+        for &(status, block, _) in &blocks {
+            switch.set_entry(u128::from(status), block);
+        }
+        switch.set_entry(u128::from(STATUS_OK), ok_block);
+        switch.emit(builder, status, output_error_block);
+
+        // A program that ran to completion still fails if its output couldn't be written
+        builder.switch_to_block(ok_block);
+        let success_block = builder.create_block();
+        builder.ins().brif(flushed, output_error_block, &[], success_block, &[]);
+        builder.switch_to_block(success_block);
+        exit(builder, 0);
+
+        for (status, block, fault) in blocks {
+            builder.switch_to_block(block);
+            match fault {
+                Ok(fault) => {
+                    let message = fault.to_string();
+                    let name = format!("fault_{}", status);
+                    let addr = data_addr(module, builder, &name, message.clone().into_bytes());
+                    let message_len = builder.ins().iconst(ptr, message.len() as i64);
+                    let index = builder.ins().load(ptr, MemFlags::trusted(), runtime, offset_of!(Runtime, error_at) as i32);
+                    let head = builder.ins().load(ptr, MemFlags::trusted(), runtime, offset_of!(Runtime, head) as i32);
+                    call(module, builder, report, &[addr, message_len, index, head]);
+                    exit(builder, 4);
+                }
+                Err(message) => {
+                    let message = format!("Error: {}\n", message);
+                    let name = format!("io_error_{}", status);
+                    let addr = data_addr(module, builder, &name, message.clone().into_bytes());
+                    let stderr = builder.ins().iconst(types::I32, 2);
+                    let message_len = builder.ins().iconst(ptr, message.len() as i64);
+                    call(module, builder, libc.write, &[stderr, addr, message_len]);
+                    exit(builder, 5);
+                }
+            }
+        }
+    })
+}
@@ -1,54 +1,415 @@
-use crate::command_opt::CommandOpt;
+use brainfetch_core::command_opt::{CommandOpt, OverflowMode};
+use crate::runtime::{self, Runtime};
+use cranelift::codegen::ir::SourceLoc;
+use cranelift::prelude::*;
+use cranelift_module::{FuncId, Linkage, Module, ModuleResult};
+use std::mem::offset_of;
+use std::ops::Range;
 
-fn put_char(ch: u8) {
-    match char::from_u32(ch as u32) {
-        Some(val) => print!("{}", val),
-        None => {},
+/// Signature of a JIT-compiled program. Takes the runtime holding the tape and read/write head and
+/// returns one of the `STATUS_*` codes.
+pub type JitFn = extern "C" fn(*mut Runtime) -> u8;
+
+/// The program ran to completion
+pub const STATUS_OK: u8 = 0;
+/// The program tried to access a cell left of the start of the tape
+pub const STATUS_UNDERFLOW: u8 = 1;
+/// Writing to the output stream failed
+pub const STATUS_OUTPUT_ERROR: u8 = 2;
+/// Reading from the input stream failed (only reported in strict input mode)
+pub const STATUS_INPUT_ERROR: u8 = 3;
+/// A cell's value can't be printed in the chosen output encoding
+pub const STATUS_INVALID_CHAR: u8 = 4;
+/// A cell went above its maximum value or below 0 with overflow checks enabled
+pub const STATUS_OVERFLOW: u8 = 5;
+/// The program used up its fuel. It can be resumed by calling the function again.
+pub const STATUS_OUT_OF_FUEL: u8 = 6;
+/// The program ran past its deadline. It can be resumed by calling the function again.
+pub const STATUS_TIMEOUT: u8 = 7;
+
+/// Where the machine code generated for each instruction ended up, so profilers can attribute
+/// samples to the source
+#[derive(Clone, Debug)]
+pub struct CodeMap {
+    /// Address of the compiled function
+    pub start: *const u8,
+    /// Size of the compiled function in bytes
+    pub size: usize,
+    /// Ranges of offsets into the function in ascending order, along with the index of the
+    /// instruction each was generated for, or none for the code that sets up and leaves the
+    /// function. Code the compiler added on its own, like the prologue or register moves, is
+    /// left out.
+    pub ranges: Vec<(Range<usize>, Option<usize>)>,
+}
+
+/// How `jit_compile` compiles a program
+#[derive(Clone, Copy, Debug)]
+pub struct JitOptions {
+    /// Width of each cell, which has to match the runtime the function is called with
+    pub cell_bits: u32,
+    /// Unless this is `OverflowMode::Wrap`, arithmetic that takes a cell out of range either
+    /// stops the function with `STATUS_OVERFLOW` or clamps the result
+    pub overflow: OverflowMode,
+    /// Range-check every tape access: the tape grows to the right on demand like the
+    /// interpreter's, and the function stops with `STATUS_UNDERFLOW` instead of touching memory
+    /// left of it. Without them, the tape is fixed at whatever size the runtime starts out with.
+    pub bounds_checks: bool,
+    /// Count executed instructions against the runtime's `fuel`, and check its limits at loop
+    /// back-edges. The function stops with `STATUS_OUT_OF_FUEL` or `STATUS_TIMEOUT` when it hits
+    /// one, and continues where it left off when called again.
+    pub metered: bool,
+}
+
+/// Values needed to address the tape from inside the compiled function
+struct Tape {
+    runtime_ptr: Value,
+    cells_var: Variable,
+    len_var: Variable,
+    head_var: Variable,
+    cell_type: Type,
+    /// log2 of the cell size in bytes
+    cell_shift: i64,
+    grow_tape: codegen::ir::FuncRef,
+    /// Block that writes the head and the index of the failing instruction back and returns the
+    /// status code. Takes the status and the instruction index as parameters.
+    error_block: Block,
+    /// Index of the instruction currently being compiled, reported by the error block
+    op_index: usize,
+    bounds_checks: bool,
+    overflow: OverflowMode,
+    /// Instructions left before the limits have to be checked again, if the program is metered
+    fuel_var: Option<Variable>,
+    /// Host function that tops the fuel up, if the program is metered
+    refuel: Option<codegen::ir::FuncRef>,
+}
+
+impl Tape {
+    // With bounds checks enabled, the head is kept inside the tape at all times (`move_head`
+    // checks every move), so the current cell and any cell at a positive offset that's been
+    // `reserve`d can be accessed without further checks. Only negative offsets are checked at
+    // the access itself.
+
+    /// Move the read/write head by `amount` and make sure it's still inside the tape: grow the
+    /// tape if the head is past its end, or branch to the fault block (with the head where it
+    /// was) if it's left of the start.
+    fn move_head(&self, builder: &mut FunctionBuilder, amount: i64) {
+        let old_head = builder.use_var(self.head_var);
+        let mem_head = builder.ins().iadd_imm(old_head, amount);
+        if !self.bounds_checks {
+            builder.def_var(self.head_var, mem_head);
+            return;
+        }
+        // A negative head wraps around to a huge unsigned one, so a single unsigned comparison
+        // catches both ends of the tape
+        let mem_len = builder.use_var(self.len_var);
+        let in_bounds = builder.ins().icmp(IntCC::UnsignedLessThan, mem_head, mem_len);
+        let cont_block = builder.create_block();
+        let out_block = builder.create_block();
+        builder.set_cold_block(out_block);
+        builder.ins().brif(in_bounds, cont_block, &[], out_block, &[]);
+
+        builder.switch_to_block(out_block);
+        let is_underflow = builder.ins().icmp_imm(IntCC::SignedLessThan, mem_head, 0);
+        let underflow = builder.ins().iconst(types::I8, i64::from(STATUS_UNDERFLOW));
+        let op_index = self.op_index(builder);
+        let grow_block = builder.create_block();
+        builder.ins().brif(is_underflow, self.error_block, &[underflow, op_index], grow_block, &[]);
+        self.grow(builder, grow_block, mem_head, cont_block);
+
+        builder.switch_to_block(cont_block);
+        builder.def_var(self.head_var, mem_head);
+    }
+
+    /// Grow the tape if needed so that the cell at `offset` (> 0) from the head is inside it
+    fn reserve(&self, builder: &mut FunctionBuilder, offset: isize) {
+        if !self.bounds_checks || offset <= 0 {
+            return;
+        }
+        let mem_head = builder.use_var(self.head_var);
+        let mem_len = builder.use_var(self.len_var);
+        let index = builder.ins().iadd_imm(mem_head, offset as i64);
+        let in_bounds = builder.ins().icmp(IntCC::UnsignedLessThan, index, mem_len);
+        let cont_block = builder.create_block();
+        let grow_block = builder.create_block();
+        builder.ins().brif(in_bounds, cont_block, &[], grow_block, &[]);
+        self.grow(builder, grow_block, index, cont_block);
+
+        builder.switch_to_block(cont_block);
     }
+
+    /// Fill the cold block `grow_block` with code that lets the host reallocate the tape so
+    /// `index` fits, picks up the tape's new location and size, and continues at `cont_block`.
+    /// The block that branches to it has to be filled already.
+    fn grow(&self, builder: &mut FunctionBuilder, grow_block: Block, index: Value, cont_block: Block) {
+        builder.set_cold_block(grow_block);
+        builder.switch_to_block(grow_block);
+        builder.ins().call(self.grow_tape, &[self.runtime_ptr, index]);
+        let (cells, len) = self.load_tape(builder);
+        builder.def_var(self.cells_var, cells);
+        builder.def_var(self.len_var, len);
+        builder.ins().jump(cont_block, &[]);
+    }
+
+    /// Address of the cell at `offset` from the read/write head. Cells at a positive offset must
+    /// have been `reserve`d first; cells at a negative one branch to the fault block if they're
+    /// left of the start of the tape.
+    fn cell_ptr(&self, builder: &mut FunctionBuilder, offset: isize) -> Value {
+        let mem_head = builder.use_var(self.head_var);
+        let index = builder.ins().iadd_imm(mem_head, offset as i64);
+        if self.bounds_checks && offset < 0 {
+            let is_underflow = builder.ins().icmp_imm(IntCC::SignedLessThan, index, 0);
+            let underflow = builder.ins().iconst(types::I8, i64::from(STATUS_UNDERFLOW));
+            self.fail_if(builder, is_underflow, underflow);
+        }
+        let mem_start = builder.use_var(self.cells_var);
+        let byte_offset = builder.ins().ishl_imm(index, self.cell_shift);
+        builder.ins().iadd(mem_start, byte_offset)
+    }
+
+    /// Stop the program if a host function returned a status other than `STATUS_OK`
+    fn check_status(&self, builder: &mut FunctionBuilder, status: Value) {
+        self.fail_if(builder, status, status);
+    }
+
+    /// Branch to the error block with `status` if `condition` is nonzero
+    fn fail_if(&self, builder: &mut FunctionBuilder, condition: Value, status: Value) {
+        let op_index = self.op_index(builder);
+        let cont_block = builder.create_block();
+        builder.ins().brif(condition, self.error_block, &[status, op_index], cont_block, &[]);
+        builder.switch_to_block(cont_block);
+    }
+
+    fn op_index(&self, builder: &mut FunctionBuilder) -> Value {
+        let ptr_type = builder.func.dfg.value_type(self.runtime_ptr);
+        builder.ins().iconst(ptr_type, self.op_index as i64)
+    }
+
+    /// Add the constant `amount` to the cell value `value`, handling overflow according to the
+    /// overflow mode
+    fn add_imm(&self, builder: &mut FunctionBuilder, value: Value, amount: i64) -> Value {
+        if self.overflow == OverflowMode::Wrap {
+            return builder.ins().iadd_imm(value, amount);
+        }
+        let magnitude = amount.unsigned_abs();
+        if magnitude > self.cell_max() {
+            // Can't be represented in the cell, so it overflows no matter what
+            let overflowed = builder.ins().iconst(types::I8, 1);
+            let result = builder.ins().iconst(self.cell_type, 0);
+            return self.handle_overflow(builder, result, overflowed, amount > 0);
+        }
+        let rhs = builder.ins().iconst(self.cell_type, magnitude as i64);
+        let (result, overflowed) = if amount > 0 {
+            builder.ins().uadd_overflow(value, rhs)
+        } else {
+            builder.ins().usub_overflow(value, rhs)
+        };
+        self.handle_overflow(builder, result, overflowed, amount > 0)
+    }
+
+    /// Add `src * factor` to the cell value `target`, handling overflow according to the overflow
+    /// mode
+    fn mul_add(&self, builder: &mut FunctionBuilder, target: Value, src: Value, factor: i64) -> Value {
+        if self.overflow == OverflowMode::Wrap {
+            let product = builder.ins().imul_imm(src, factor);
+            return builder.ins().iadd(target, product);
+        }
+        let magnitude = factor.unsigned_abs();
+        let (product, product_overflowed) = if magnitude > self.cell_max() {
+            // Any nonzero source overflows
+            let overflowed = builder.ins().icmp_imm(IntCC::NotEqual, src, 0);
+            (builder.ins().iconst(self.cell_type, 0), overflowed)
+        } else {
+            let rhs = builder.ins().iconst(self.cell_type, magnitude as i64);
+            builder.ins().umul_overflow(src, rhs)
+        };
+        let (result, sum_overflowed) = if factor > 0 {
+            builder.ins().uadd_overflow(target, product)
+        } else {
+            builder.ins().usub_overflow(target, product)
+        };
+        // If the product overflowed, the sum would have too
+        let overflowed = builder.ins().bor(product_overflowed, sum_overflowed);
+        self.handle_overflow(builder, result, overflowed, factor > 0)
+    }
+
+    /// Pick the value to store after an addition that went up (`upwards`) or down, given whether
+    /// it `overflowed`: stop the program in checked mode, or clamp in saturating mode
+    fn handle_overflow(&self, builder: &mut FunctionBuilder, result: Value, overflowed: Value, upwards: bool) -> Value {
+        match self.overflow {
+            OverflowMode::Check => {
+                let status = builder.ins().iconst(types::I8, i64::from(STATUS_OVERFLOW));
+                self.fail_if(builder, overflowed, status);
+                result
+            }
+            _ => {
+                let bound = if upwards { self.cell_max() as i64 } else { 0 };
+                let bound = builder.ins().iconst(self.cell_type, bound);
+                builder.ins().select(overflowed, bound, result)
+            }
+        }
+    }
+
+    /// Largest value a cell can hold
+    fn cell_max(&self) -> u64 {
+        u64::MAX >> (64 - self.cell_type.bits())
+    }
+
+    /// Load the tape's current start address and length from the runtime
+    fn load_tape(&self, builder: &mut FunctionBuilder) -> (Value, Value) {
+        let ptr_type = builder.func.dfg.value_type(self.runtime_ptr);
+        let cells = builder.ins().load(ptr_type, MemFlags::trusted(), self.runtime_ptr, offset_of!(Runtime, cells) as i32);
+        let len = builder.ins().load(ptr_type, MemFlags::trusted(), self.runtime_ptr, offset_of!(Runtime, len) as i32);
+        (cells, len)
+    }
+
+    /// Store the read/write head and the remaining fuel back into the runtime
+    fn store_state(&self, builder: &mut FunctionBuilder) {
+        let mem_head = builder.use_var(self.head_var);
+        builder.ins().store(MemFlags::trusted(), mem_head, self.runtime_ptr, offset_of!(Runtime, head) as i32);
+        if let Some(fuel_var) = self.fuel_var {
+            let fuel = builder.use_var(fuel_var);
+            builder.ins().store(MemFlags::trusted(), fuel, self.runtime_ptr, offset_of!(Runtime, fuel) as i32);
+        }
+    }
+
+    /// Charge `count` instructions against the fuel
+    fn consume(&self, builder: &mut FunctionBuilder, count: usize) {
+        if let Some(fuel_var) = self.fuel_var {
+            let fuel = builder.use_var(fuel_var);
+            let fuel = builder.ins().iadd_imm(fuel, -(count as i64));
+            builder.def_var(fuel_var, fuel);
+        }
+    }
+
+    /// Let the host check the program's limits once the fuel ran out, and stop the program if it
+    /// hit one. The program resumes at the current instruction when it's called again, so this
+    /// has to come before anything the instruction does.
+    fn safepoint(&self, builder: &mut FunctionBuilder) {
+        let (Some(fuel_var), Some(refuel)) = (self.fuel_var, self.refuel) else {
+            return;
+        };
+        let fuel = builder.use_var(fuel_var);
+        let exhausted = builder.ins().icmp_imm(IntCC::SignedLessThan, fuel, 0);
+        let cont_block = builder.create_block();
+        let refuel_block = builder.create_block();
+        builder.set_cold_block(refuel_block);
+        builder.ins().brif(exhausted, refuel_block, &[], cont_block, &[]);
+
+        builder.switch_to_block(refuel_block);
+        builder.ins().store(MemFlags::trusted(), fuel, self.runtime_ptr, offset_of!(Runtime, fuel) as i32);
+        let call = builder.ins().call(refuel, &[self.runtime_ptr]);
+        let status = builder.inst_results(call)[0];
+        // Reload the fuel first, so stopping here doesn't drop what `refuel` topped up
+        let fuel = builder.ins().load(types::I64, MemFlags::trusted(), self.runtime_ptr, offset_of!(Runtime, fuel) as i32);
+        builder.def_var(fuel_var, fuel);
+        self.fail_if(builder, status, status);
+        builder.ins().jump(cont_block, &[]);
+
+        builder.switch_to_block(cont_block);
+    }
+}
+
+/// Whether `cmd` always continues with the next instruction without moving the head
+fn is_straight_line(cmd: &CommandOpt) -> bool {
+    matches!(
+        cmd,
+        CommandOpt::ChVal { .. }
+            | CommandOpt::PutChar { .. }
+            | CommandOpt::GetChar { .. }
+            | CommandOpt::Zero { .. }
+            | CommandOpt::MulAdd { .. }
+    )
 }
 
-fn get_char() -> u8 {
-    use std::io::Read;
-    let mut buffer = [0u8];
-    // Throw away error (if no stdin, just keep it at 0)
-    let _ = std::io::stdin().read_exact(&mut buffer);
-    buffer[0]
+/// Whether the compiled code checks the program's limits at `cmd`, so it can also be resumed
+/// there
+fn is_safepoint(cmd: &CommandOpt) -> bool {
+    // Scans always end once they reach the untouched part of the tape, so only actual loops need
+    // to be interruptible
+    matches!(cmd, CommandOpt::CloseBr(_) | CommandOpt::LoopForever)
 }
 
 /// Given a BF program represented with a Vec of the `CommandOpt` data structure, compile it into
 /// native code to be executed on the host machine.
 ///
-/// Cranelift is awesome! Have a look at the `match` statement in here to see what CraneLift IR
-/// codes I'm mapping each instruction to
-pub fn jit_compile(program: &Vec<CommandOpt>) -> cranelift_module::ModuleResult<fn(*mut u8, *mut usize)> {
-    use cranelift::prelude::*;
-    use cranelift_module::{Linkage, Module};
+/// When the compiled function stops with an error, the runtime's `error_at` holds the index of
+/// the instruction that caused it. The `CodeMap` tells where each instruction's code is.
+#[allow(clippy::result_large_err)]
+pub fn jit_compile(program: &[CommandOpt], options: &JitOptions) -> ModuleResult<(JitFn, CodeMap)> {
     use cranelift_jit::{JITBuilder, JITModule};
 
     // Create JIT builder and module
     let mut builder = JITBuilder::with_flags(&[("opt_level", "speed")], cranelift_module::default_libcall_names())?;
 
     // Register host functions
-    builder.symbol("put_char", put_char as *const u8);
-    builder.symbol("get_char", get_char as *const u8);
+    builder.symbol("put_char", runtime::put_char as *const u8);
+    builder.symbol("get_char", runtime::get_char as *const u8);
+    builder.symbol("grow_tape", runtime::grow_tape as *const u8);
+    builder.symbol("refuel", runtime::refuel as *const u8);
 
     let mut module = JITModule::new(builder);
+    let (func_id, size, ranges) = define_program(&mut module, program, options)?;
+    module.finalize_definitions()?;
+
+    let code_ptr = module.get_finalized_function(func_id);
+    let code_map = CodeMap { start: code_ptr, size, ranges };
+    // Return a callable function (declare it as a function pointer)
+    Ok((unsafe {std::mem::transmute::<*const u8, JitFn>(code_ptr)}, code_map))
+}
+
+/// Define the compiled program in `module` as an exported function named `execute`, with the
+/// signature of `JitFn`. It calls the host functions `put_char`, `get_char`, `grow_tape` and
+/// (if metered) `refuel`, which `module` has to provide, and accesses the `Runtime` it's
+/// passed by its Rust layout, so the module has to target the host.
+///
+/// Returns the function's ID, the size of its code in bytes, and the ranges of that code each
+/// instruction was compiled to, as described for `CodeMap::ranges`.
+///
+/// Cranelift is awesome! Have a look at the `match` statement in here to see what CraneLift IR
+/// codes I'm mapping each instruction to
+#[allow(clippy::result_large_err, clippy::type_complexity)]
+pub fn define_program<M: Module>(
+    module: &mut M,
+    program: &[CommandOpt],
+    options: &JitOptions,
+) -> ModuleResult<(FuncId, usize, Vec<(Range<usize>, Option<usize>)>)> {
+    let JitOptions { cell_bits, overflow, bounds_checks, metered } = *options;
 
     let ptr_type = module.target_config().pointer_type();
+    let cell_type = Type::int(cell_bits as u16).expect("cell width must be 8, 16, 32 or 64 bits");
 
     let mut sig = module.make_signature();
-    sig.params.push(AbiParam::new(ptr_type)); // memory pointer
-    sig.params.push(AbiParam::new(ptr_type)); // mem_ptr value
+    sig.params.push(AbiParam::new(ptr_type)); // runtime pointer
+    sig.returns.push(AbiParam::new(types::I8)); // status code
 
     // Import function signatures
     let mut put_sig = module.make_signature();
-    put_sig.params.push(AbiParam::new(types::I8)); // takes one u8
+    put_sig.params.push(AbiParam::new(ptr_type)); // runtime pointer
+    put_sig.params.push(AbiParam::new(types::I64)); // cell value, zero-extended
+    put_sig.returns.push(AbiParam::new(types::I8)); // status code
     let put_func_id = module.declare_function("put_char", Linkage::Import, &put_sig)?;
 
     let mut get_sig = module.make_signature();
-    get_sig.returns.push(AbiParam::new(types::I8)); // returns u8
+    get_sig.params.push(AbiParam::new(ptr_type)); // runtime pointer
+    get_sig.params.push(AbiParam::new(ptr_type)); // cell to read into
+    get_sig.returns.push(AbiParam::new(types::I8)); // status code
     let get_func_id = module.declare_function("get_char", Linkage::Import, &get_sig)?;
 
+    let mut grow_sig = module.make_signature();
+    grow_sig.params.push(AbiParam::new(ptr_type)); // runtime pointer
+    grow_sig.params.push(AbiParam::new(ptr_type)); // index of the cell that has to fit
+    let grow_func_id = module.declare_function("grow_tape", Linkage::Import, &grow_sig)?;
+
+    // Only metered programs call back to refuel, so hosts that never meter don't need it
+    let refuel_func_id = if metered {
+        let mut refuel_sig = module.make_signature();
+        refuel_sig.params.push(AbiParam::new(ptr_type)); // runtime pointer
+        refuel_sig.returns.push(AbiParam::new(types::I8)); // status code
+        Some(module.declare_function("refuel", Linkage::Import, &refuel_sig)?)
+    } else {
+        None
+    };
+
     // Declare the function
     let res_func_id = module.declare_function("execute", Linkage::Export, &sig)?;
 
@@ -59,92 +420,391 @@ pub fn jit_compile(program: &Vec<CommandOpt>) -> cranelift_module::ModuleResult<
     let mut func_ctx = FunctionBuilderContext::new();
     let mut builder = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
 
-    // Create a block for each command in the program
+    // Create a block for each command in the program, plus an entry block (the first command
+    // may be a loop target, which the entry block isn't allowed to be)
+    let entry_block = builder.create_block();
     let mut blocks: Vec<Block> = program.iter().map(|_| builder.create_block()).collect();
     let exit_block = builder.create_block();
     blocks.push(exit_block);
-    builder.append_block_params_for_function_params(blocks[0]);
+    builder.append_block_params_for_function_params(entry_block);
 
-    // Load function parameters
-    let mem_start = builder.block_params(blocks[0])[0]; // Address of start of virtual memory region
-    let mem_head_ptr = builder.block_params(blocks[0])[1]; // Address of virtual memory read/write head
+    let error_block = builder.create_block();
+    builder.append_block_param(error_block, types::I8);
+    builder.append_block_param(error_block, ptr_type);
+    builder.set_cold_block(error_block);
 
     // Connect to imported Rust functions
-    let local_put = module.declare_func_in_func(put_func_id, &mut builder.func);
-    let local_get = module.declare_func_in_func(get_func_id, &mut builder.func);
+    let local_put = module.declare_func_in_func(put_func_id, builder.func);
+    let local_get = module.declare_func_in_func(get_func_id, builder.func);
+    let local_grow = module.declare_func_in_func(grow_func_id, builder.func);
+    let local_refuel = refuel_func_id.map(|func_id| module.declare_func_in_func(func_id, builder.func));
+
+    // Load function parameters
+    builder.switch_to_block(entry_block);
+    // Code is tagged with the index of the instruction it's for, or the program's length if
+    // it's for none of them
+    let outside = SourceLoc::new(program.len() as u32);
+    builder.set_srcloc(outside);
+    let runtime_ptr = builder.block_params(entry_block)[0]; // Address of the host's `Runtime`
 
+    // The tape's location, size and read/write head (and the fuel) live in SSA variables for the
+    // whole function, so they can stay in registers. They're only loaded here and after calling
+    // back into the host, and written back in the exit and fault blocks.
+    let cells_var = Variable::from_u32(0);
+    let len_var = Variable::from_u32(1);
+    let head_var = Variable::from_u32(2);
+    builder.declare_var(cells_var, ptr_type);
+    builder.declare_var(len_var, ptr_type);
+    builder.declare_var(head_var, ptr_type);
+    let fuel_var = metered.then(|| {
+        let fuel_var = Variable::from_u32(3);
+        builder.declare_var(fuel_var, types::I64);
+        fuel_var
+    });
+
+    let mut tape = Tape { runtime_ptr, cells_var, len_var, head_var, cell_type, cell_shift: cell_type.bytes().ilog2() as i64, grow_tape: local_grow, error_block, op_index: 0, bounds_checks, overflow, fuel_var, refuel: local_refuel };
+
+    let (cells, len) = tape.load_tape(&mut builder);
+    builder.def_var(cells_var, cells);
+    builder.def_var(len_var, len);
+    let initial_head = builder.ins().load(ptr_type, MemFlags::trusted(), runtime_ptr, offset_of!(Runtime, head) as i32);
+    builder.def_var(head_var, initial_head);
+    match fuel_var {
+        Some(fuel_var) => {
+            let fuel = builder.ins().load(types::I64, MemFlags::trusted(), runtime_ptr, offset_of!(Runtime, fuel) as i32);
+            builder.def_var(fuel_var, fuel);
+
+            // Continue where the last call stopped. Only the start and safepoints are valid
+            // places to resume at.
+            let mut resume_at = builder.ins().load(ptr_type, MemFlags::trusted(), runtime_ptr, offset_of!(Runtime, resume_at) as i32);
+            if ptr_type != types::I32 {
+                resume_at = builder.ins().ireduce(types::I32, resume_at);
+            }
+            let targets: Vec<_> = program
+                .iter()
+                .enumerate()
+                .map(|(i, cmd)| {
+                    let target = if i == 0 || is_safepoint(cmd) { blocks[i] } else { exit_block };
+                    builder.func.dfg.block_call(target, &[])
+                })
+                .collect();
+            let default = builder.func.dfg.block_call(blocks[0], &[]);
+            let jump_table = builder.create_jump_table(JumpTableData::new(default, &targets));
+            builder.ins().br_table(resume_at, jump_table);
+        }
+        None => {
+            builder.ins().jump(blocks[0], &[]);
+        }
+    }
 
     for (i, cmd) in program.iter().enumerate() {
         builder.switch_to_block(blocks[i]);
+        builder.set_srcloc(SourceLoc::new(i as u32));
+        tape.op_index = i;
 
-        let mem_head = builder.ins().load(ptr_type, MemFlags::new(), mem_head_ptr, 0); // Read/write head
+        // Runs of straight-line instructions can only be entered at their first instruction, so
+        // grow the tape for the whole run up front
+        if is_straight_line(cmd) && (i == 0 || !is_straight_line(&program[i - 1])) {
+            let max_offset = program[i..]
+                .iter()
+                .take_while(|cmd| is_straight_line(cmd))
+                .filter_map(|cmd| match cmd {
+                    CommandOpt::ChVal { offset, .. }
+                    | CommandOpt::PutChar { offset }
+                    | CommandOpt::GetChar { offset }
+                    | CommandOpt::Zero { offset }
+                    | CommandOpt::MulAdd { offset, .. } => Some(*offset),
+                    _ => None,
+                })
+                .max()
+                .unwrap_or(0);
+            tape.reserve(&mut builder, max_offset);
+            let run_len = program[i..].iter().take_while(|cmd| is_straight_line(cmd)).count();
+            tape.consume(&mut builder, run_len);
+        } else if is_safepoint(cmd) {
+            // Charged once past the check, so resuming here doesn't charge it a second time
+            tape.safepoint(&mut builder);
+            tape.consume(&mut builder, 1);
+        } else if !is_straight_line(cmd) {
+            tape.consume(&mut builder, 1);
+        }
 
         match cmd {
             CommandOpt::ChPtr(value) => {
-                let new_val = builder.ins().iadd_imm(mem_head, *value as i64);
-                builder.ins().store(MemFlags::new(), new_val, mem_head_ptr, 0);
+                tape.move_head(&mut builder, *value as i64);
                 builder.ins().jump(blocks[i + 1], &[]);
             }
-            CommandOpt::ChVal(value) => {
-                let curr_cell_ptr = builder.ins().iadd(mem_start, mem_head);
+            CommandOpt::ChVal { offset, amount } => {
+                let curr_cell_ptr = tape.cell_ptr(&mut builder, *offset);
 
-                let old_val = builder.ins().load(types::I8, MemFlags::new(), curr_cell_ptr, 0);
-                let new_val = builder.ins().iadd_imm(old_val, i64::from(*value));
+                let old_val = builder.ins().load(cell_type, MemFlags::new(), curr_cell_ptr, 0);
+                let new_val = tape.add_imm(&mut builder, old_val, *amount);
 
                 builder.ins().store(MemFlags::new(), new_val, curr_cell_ptr, 0);
                 builder.ins().jump(blocks[i + 1], &[]);
             }
-            CommandOpt::PutChar => {
-                let curr_cell_ptr = builder.ins().iadd(mem_start, mem_head);
-                let curr_val = builder.ins().load(types::I8, MemFlags::new(), curr_cell_ptr, 0);
-                builder.ins().call(local_put, &[curr_val]);
+            CommandOpt::PutChar { offset } => {
+                let curr_cell_ptr = tape.cell_ptr(&mut builder, *offset);
+                let mut curr_val = builder.ins().load(cell_type, MemFlags::new(), curr_cell_ptr, 0);
+                if cell_type != types::I64 {
+                    curr_val = builder.ins().uextend(types::I64, curr_val);
+                }
+                let call = builder.ins().call(local_put, &[runtime_ptr, curr_val]);
+                let status = builder.inst_results(call)[0];
+                tape.check_status(&mut builder, status);
                 builder.ins().jump(blocks[i + 1], &[]);
             }
-            CommandOpt::GetChar => {
-                let curr_cell_ptr = builder.ins().iadd(mem_start, mem_head);
-                let call = builder.ins().call(local_get, &[]);
-                let result = builder.inst_results(call)[0];
-                builder.ins().store(MemFlags::new(), result, curr_cell_ptr, 0);
+            CommandOpt::GetChar { offset } => {
+                let curr_cell_ptr = tape.cell_ptr(&mut builder, *offset);
+                let call = builder.ins().call(local_get, &[runtime_ptr, curr_cell_ptr]);
+                let status = builder.inst_results(call)[0];
+                tape.check_status(&mut builder, status);
                 builder.ins().jump(blocks[i + 1], &[]);
             }
-            CommandOpt::Zero => {
-                let curr_cell_ptr = builder.ins().iadd(mem_start, mem_head);
-                let zero = builder.ins().iconst(types::I8, 0);
+            CommandOpt::Zero { offset } => {
+                let curr_cell_ptr = tape.cell_ptr(&mut builder, *offset);
+                let zero = builder.ins().iconst(cell_type, 0);
                 builder.ins().store(MemFlags::new(), zero, curr_cell_ptr, 0);
                 builder.ins().jump(blocks[i + 1], &[]);
             }
+            CommandOpt::MulAdd { offset, factor } => {
+                let curr_cell_ptr = tape.cell_ptr(&mut builder, 0);
+                let curr_val = builder.ins().load(cell_type, MemFlags::new(), curr_cell_ptr, 0);
+                if (bounds_checks && *offset < 0) || overflow != OverflowMode::Wrap {
+                    // The original loop never runs (or touches the target) if the cell is zero,
+                    // which also saves checking for overflow
+                    let add_block = builder.create_block();
+                    builder.ins().brif(curr_val, add_block, &[], blocks[i + 1], &[]);
+                    builder.switch_to_block(add_block);
+                }
+                let target_ptr = tape.cell_ptr(&mut builder, *offset);
+
+                let old_val = builder.ins().load(cell_type, MemFlags::new(), target_ptr, 0);
+                let new_val = tape.mul_add(&mut builder, old_val, curr_val, *factor);
+
+                builder.ins().store(MemFlags::new(), new_val, target_ptr, 0);
+                builder.ins().jump(blocks[i + 1], &[]);
+            }
+            CommandOpt::Scan(stride) => {
+                // Step the head by `stride` until the cell is zero
+                let test_block = builder.create_block();
+                let step_block = builder.create_block();
+                builder.ins().jump(test_block, &[]);
+
+                builder.switch_to_block(test_block);
+                let curr_cell_ptr = tape.cell_ptr(&mut builder, 0);
+                let curr_val = builder.ins().load(cell_type, MemFlags::new(), curr_cell_ptr, 0);
+                builder.ins().brif(curr_val, step_block, &[], blocks[i+1], &[]);
+
+                builder.switch_to_block(step_block);
+                tape.move_head(&mut builder, *stride as i64);
+                builder.ins().jump(test_block, &[]);
+            }
             CommandOpt::LoopForever => {
-                let curr_cell_ptr = builder.ins().iadd(mem_start, mem_head);
-                let curr_val = builder.ins().load(types::I8, MemFlags::new(), curr_cell_ptr, 0);
+                let curr_cell_ptr = tape.cell_ptr(&mut builder, 0);
+                let curr_val = builder.ins().load(cell_type, MemFlags::new(), curr_cell_ptr, 0);
                 builder.ins().brif(curr_val, blocks[i], &[], blocks[i+1], &[]);
             }
             CommandOpt::OpenBr(dest) => {
-                let curr_cell_ptr = builder.ins().iadd(mem_start, mem_head);
-                let curr_val = builder.ins().load(types::I8, MemFlags::new(), curr_cell_ptr, 0);
-                builder.ins().brif(curr_val, blocks[i+1], &[], blocks[*dest], &[]);
+                let curr_cell_ptr = tape.cell_ptr(&mut builder, 0);
+                let curr_val = builder.ins().load(cell_type, MemFlags::new(), curr_cell_ptr, 0);
+                builder.ins().brif(curr_val, blocks[i+1], &[], blocks[*dest + 1], &[]);
             }
             CommandOpt::CloseBr(dest) => {
-                let curr_cell_ptr = builder.ins().iadd(mem_start, mem_head);
-                let curr_val = builder.ins().load(types::I8, MemFlags::new(), curr_cell_ptr, 0);
-                builder.ins().brif(curr_val, blocks[*dest], &[], blocks[i+1], &[]);
+                let curr_cell_ptr = tape.cell_ptr(&mut builder, 0);
+                let curr_val = builder.ins().load(cell_type, MemFlags::new(), curr_cell_ptr, 0);
+                builder.ins().brif(curr_val, blocks[*dest + 1], &[], blocks[i+1], &[]);
             }
         }
-        builder.seal_block(blocks[i]);
     }
-    // Put `return` call at the exit block
+    // Write the read/write head back and `return` at the exit block
+    builder.set_srcloc(outside);
     builder.switch_to_block(exit_block);
-    builder.seal_block(exit_block);
-    builder.ins().return_(&[]);
+    tape.store_state(&mut builder);
+    let ok = builder.ins().iconst(types::I8, i64::from(STATUS_OK));
+    builder.ins().return_(&[ok]);
+
+    // Accesses left of the tape, overflowing cells, failed host calls and interruptions end up here
+    builder.switch_to_block(error_block);
+    tape.store_state(&mut builder);
+    let status = builder.block_params(error_block)[0];
+    let error_at = builder.block_params(error_block)[1];
+    builder.ins().store(MemFlags::trusted(), error_at, runtime_ptr, offset_of!(Runtime, error_at) as i32);
+    builder.ins().return_(&[status]);
+
+    // Loops jump backwards, so blocks can only be sealed once every branch is in place
+    builder.seal_all_blocks();
 
     builder.finalize();
 
     // Define function body in the module
     module.define_function(res_func_id, &mut ctx)?;
+    let compiled = ctx.compiled_code().expect("the function was just compiled");
+    let size = compiled.buffer.total_size() as usize;
+    let ranges = compiled
+        .buffer
+        .get_srclocs_sorted()
+        .iter()
+        .filter(|srcloc| !srcloc.loc.is_default())
+        .map(|srcloc| {
+            let index = (srcloc.loc != outside).then_some(srcloc.loc.bits() as usize);
+            (srcloc.start as usize..srcloc.end as usize, index)
+        })
+        .collect();
     module.clear_context(&mut ctx);
-    module.finalize_definitions()?;
-
-    let code_ptr = module.get_finalized_function(res_func_id);
-    // Return a callable function (declare it as a function pointer)
-    Ok(unsafe {std::mem::transmute::<*const u8, fn(*mut u8, *mut usize)>(code_ptr)})
+    Ok((res_func_id, size, ranges))
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use brainfetch_core::io::{EofBehavior, Input, Output, OutputEncoding};
+    use brainfetch_core::{command_opt, Error, Limits, Machine};
+    use std::io::{ErrorKind, Read};
+
+    /// Reader that fails every read
+    struct Broken;
+
+    impl Read for Broken {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            Err(ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    /// Program and I/O setup to run through both the interpreter and the JIT
+    struct Case<'a> {
+        code: &'a str,
+        input: &'a [u8],
+        broken_input: bool,
+        cell_bits: u32,
+        eof: EofBehavior,
+        strict: bool,
+        encoding: OutputEncoding,
+    }
+
+    impl Case<'_> {
+        fn input(&self) -> Input<Box<dyn Read + '_>> {
+            match self.broken_input {
+                true => Input::new(Broken, self.eof, self.strict).boxed(),
+                false => Input::new(self.input, self.eof, self.strict).boxed(),
+            }
+        }
+
+        /// What the program wrote, and the error it stopped with, if any
+        fn interpret(&self) -> (Vec<u8>, Option<String>) {
+            let prg = command_opt::parse(self.code, self.cell_bits, OverflowMode::Wrap).unwrap();
+            let (mut input, mut output) = (self.input(), Output::new(Vec::new(), self.encoding));
+            let (overflow, limits) = (OverflowMode::Wrap, Limits::default());
+            let result = match self.cell_bits {
+                8 => Machine::<u8>::new().run(&prg, overflow, &limits, &mut input, &mut output),
+                16 => Machine::<u16>::new().run(&prg, overflow, &limits, &mut input, &mut output),
+                32 => Machine::<u32>::new().run(&prg, overflow, &limits, &mut input, &mut output),
+                _ => Machine::<u64>::new().run(&prg, overflow, &limits, &mut input, &mut output),
+            };
+            (output.into_inner(), result.err().map(|err| err.to_string()))
+        }
+
+        fn jit(&self) -> (Vec<u8>, Option<String>) {
+            let prg = command_opt::parse(self.code, self.cell_bits, OverflowMode::Wrap).unwrap();
+            let options = JitOptions {
+                cell_bits: self.cell_bits,
+                overflow: OverflowMode::Wrap,
+                bounds_checks: true,
+                metered: false,
+            };
+            let (program, _) = jit_compile(&prg.commands, &options).unwrap();
+            let mut written = Vec::new();
+            let output = Output::new(&mut written, self.encoding);
+            let mut runtime = Runtime::new(30_000, self.cell_bits, self.input(), output);
+            let result = runtime.run(program, &prg.spans);
+            drop(runtime);
+            (written, result.err().map(|err| err.to_string()))
+        }
+
+        fn check(&self) {
+            assert_eq!(self.jit(), self.interpret(), "for {:?}", self.code);
+        }
+    }
+
+    const DEFAULT: Case = Case {
+        code: "",
+        input: b"",
+        broken_input: false,
+        cell_bits: 8,
+        eof: EofBehavior::Zero,
+        strict: false,
+        encoding: OutputEncoding::Raw,
+    };
+
+    #[test]
+    fn eof_behaviors_match_interpreter() {
+        for eof in [EofBehavior::Zero, EofBehavior::Max, EofBehavior::Unchanged] {
+            for cell_bits in [8, 16, 32, 64] {
+                // The third read sees a cell that was set to 7 before
+                let code = ",.,.+++++++,.";
+                Case { code, input: b"A", cell_bits, eof, ..DEFAULT }.check();
+            }
+        }
+    }
+
+    #[test]
+    fn strict_input_matches_interpreter() {
+        for strict in [false, true] {
+            let code = "+,.";
+            Case { code, broken_input: true, strict, eof: EofBehavior::Max, ..DEFAULT }.check();
+        }
+    }
+
+    #[test]
+    fn output_encodings_match_interpreter() {
+        // Prints 'A', then 0xE9, then 0x20AC, which only fits from 16 bits up
+        let [a, e_acute, euro] = [0x41, 0xE9, 0x20AC].map(|value| "+".repeat(value));
+        let code = format!("{}.[-]{}.[-]{}.", a, e_acute, euro);
+        for encoding in [OutputEncoding::Raw, OutputEncoding::Utf8, OutputEncoding::Latin1] {
+            for cell_bits in [8, 16, 32] {
+                Case { code: &code, cell_bits, encoding, ..DEFAULT }.check();
+            }
+        }
+        // Surrogates aren't characters
+        let code = format!("{}.", "+".repeat(0xD800));
+        Case { code: &code, cell_bits: 16, encoding: OutputEncoding::Utf8, ..DEFAULT }.check();
+    }
+
+    /// Calls it takes to run `code` to the end when each call only gets one instruction's worth
+    /// of fuel, or `None` if it doesn't finish within `max_calls`
+    fn calls_with_fuel_1(code: &str, jit: bool, max_calls: usize) -> Option<usize> {
+        let prg = command_opt::parse(code, 8, OverflowMode::Wrap).unwrap();
+        let limits = Limits { fuel: Some(1), deadline: None };
+        let (mut input, mut output) = (DEFAULT.input(), Output::new(Vec::new(), DEFAULT.encoding));
+        let mut machine = Machine::<u8>::new();
+        let options =
+            JitOptions { cell_bits: 8, overflow: OverflowMode::Wrap, bounds_checks: true, metered: true };
+        let (program, _) = jit_compile(&prg.commands, &options).unwrap();
+        let mut runtime = Runtime::new(30_000, 8, DEFAULT.input(), Output::new(Vec::new(), DEFAULT.encoding));
+        for calls in 1..=max_calls {
+            let result = match jit {
+                true => {
+                    runtime.set_limits(&limits);
+                    runtime.run(program, &prg.spans)
+                }
+                false => machine.run(&prg, OverflowMode::Wrap, &limits, &mut input, &mut output),
+            };
+            match result {
+                Ok(()) => return Some(calls),
+                Err(Error::Interrupted { .. }) => {}
+                Err(err) => panic!("{}", err),
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn resuming_at_loop_charges_it_once() {
+        // Every call stops at the `]`, so charging it again when resuming there would never
+        // leave fuel for the loop to go on
+        let code = "+++[.-]";
+        let interpreted = calls_with_fuel_1(code, false, 100).unwrap();
+        let compiled = calls_with_fuel_1(code, true, 100).expect("the JIT never finished");
+        assert!(compiled <= interpreted, "{} calls, the interpreter took {}", compiled, interpreted);
+    }
+}
@@ -1,45 +1,163 @@
-use std::path::PathBuf;
-use clap::Parser;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+use clap::{Args, Parser, Subcommand};
+use brainfetch_core::{command_opt, io, Error, Limits};
 
-mod command;
-mod command_opt;
+mod aot;
 mod jit;
+mod perf;
+mod runtime;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
     /// BrainF*** file to execute
-    file: PathBuf,
+    #[arg(required = true)]
+    file: Option<PathBuf>,
+
+    #[command(flatten)]
+    options: Options,
+
+    /// Stop the program after executing this many instructions. Only checked at loops, so the
+    /// program may run slightly past it.
+    #[arg(long, value_name = "INSTRUCTIONS")]
+    fuel: Option<u64>,
+
+    /// Stop the program once it has been running for this long
+    #[arg(long, value_name = "SECONDS", value_parser = parse_timeout)]
+    timeout: Option<Duration>,
+
+    /// Write /tmp/perf-<PID>.map, so `perf report` can name the compiled code after the loops it
+    /// came from
+    #[arg(long)]
+    perf_map: bool,
+
+    /// Write a jitdump file to this directory for `perf inject --jit`, so `perf annotate` can also
+    /// show the compiled code with source lines. Needs `perf record -k mono`.
+    #[arg(long, value_name = "DIR")]
+    jitdump: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Compile a program ahead of time, to an object file or an executable that doesn't need
+    /// the JIT
+    Compile(aot::CompileArgs),
+}
+
+/// How the program's cells and I/O behave, shared with `compile`
+#[derive(Args)]
+#[command(about = None, long_about = None)]
+struct Options {
+    /// How cell values are written to stdout
+    #[arg(long, value_enum, default_value_t)]
+    output_encoding: io::OutputEncoding,
+
+    /// What `,` stores in the cell once stdin is exhausted
+    #[arg(long, value_enum, default_value_t)]
+    eof: io::EofBehavior,
+
+    /// Fail on stdin read errors instead of treating them like the end of input
+    #[arg(long)]
+    strict_input: bool,
+
+    /// Width of each cell on the tape, in bits
+    #[arg(long, default_value_t = 8, value_parser = parse_cell_bits)]
+    cell_bits: u32,
+
+    /// Skip range checks on tape accesses. Faster, but the tape is fixed at 30,000 cells and a
+    /// program that walks off either end of it will corrupt the host process, so only use this
+    /// for trusted programs.
+    #[arg(long)]
+    unchecked: bool,
+
+    /// What happens when a cell goes above its maximum value or below 0
+    #[arg(long, value_enum, default_value_t)]
+    overflow: command_opt::OverflowMode,
+}
+
+fn parse_cell_bits(arg: &str) -> Result<u32, String> {
+    match arg.parse() {
+        Ok(bits @ (8 | 16 | 32 | 64)) => Ok(bits),
+        _ => Err(String::from("cell width must be 8, 16, 32 or 64")),
+    }
+}
+
+fn parse_timeout(arg: &str) -> Result<Duration, String> {
+    arg.parse()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .ok_or_else(|| String::from("timeout must be a non-negative number of seconds"))
 }
 
-fn main() -> Result<(), &'static str> {
+fn main() -> ExitCode {
     let cli = Cli::parse();
+    let file = match &cli.command {
+        Some(Commands::Compile(args)) => &args.file,
+        None => cli.file.as_ref().expect("clap requires a file without a subcommand"),
+    };
 
-    let contents: String = match std::fs::read_to_string(&cli.file) {
+    let contents: String = match std::fs::read_to_string(file) {
         Ok(data) => data,
-        Err(err) => {
-            eprintln!("Can't open file '{}': {}", cli.file.to_string_lossy(), err);
-            return Err("Unable to open specified file.")
+        Err(source) => {
+            let err = Error::Open { path: file.clone(), source };
+            eprint!("{}", err.report(""));
+            return ExitCode::from(err.exit_code());
         }
     };
 
-    let tokens = command_opt::tokenize(&contents)?;
-
-    let program = match jit::jit_compile(&tokens) {
-        Ok(result) => result,
-        Err(error) => {
-            eprintln!("{}", error);
-            return Err("Failed to generate JIT program.")
+    let result = match &cli.command {
+        Some(Commands::Compile(args)) => aot::run(args, &contents),
+        None => run(&cli, file, &contents),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprint!("{}", err.report(&contents));
+            ExitCode::from(err.exit_code())
         }
+    }
+}
+
+fn run(cli: &Cli, file: &Path, contents: &str) -> Result<(), Error> {
+    let cli_options = &cli.options;
+    let tokens = command_opt::parse(contents, cli_options.cell_bits, cli_options.overflow)
+        .map_err(Error::Parse)?;
+
+    let options = jit::JitOptions {
+        cell_bits: cli_options.cell_bits,
+        overflow: cli_options.overflow,
+        bounds_checks: !cli_options.unchecked,
+        metered: cli.fuel.is_some() || cli.timeout.is_some(),
     };
+    let (program, code_map) = jit::jit_compile(&tokens.commands, &options)
+        .map_err(|err| Error::Compile(Box::new(err)))?;
+
+    if cli.perf_map || cli.jitdump.is_some() {
+        let root = file.file_name().unwrap_or(file.as_os_str()).to_string_lossy();
+        let symbols = perf::symbols(&code_map, &tokens, contents, &root);
+        if cli.perf_map {
+            perf::write_perf_map(&symbols)?;
+        }
+        if let Some(dir) = &cli.jitdump {
+            perf::write_jitdump(dir, &symbols, file)?;
+        }
+    }
 
     // Set up starting state of program
-    let mut memory = [0u8; 30_000];
-    let mut mem_ptr: usize = 0;
+    let input = io::Input::stdin(cli_options.eof, cli_options.strict_input);
+    let output = io::Output::stdout(cli_options.output_encoding);
+    let mut runtime = runtime::Runtime::new(30_000, cli_options.cell_bits, input, output);
+    runtime.set_limits(&Limits {
+        fuel: cli.fuel,
+        deadline: cli.timeout.map(|timeout| Instant::now() + timeout),
+    });
 
     // Call the JIT function
-    program(memory.as_mut_ptr(), &mut mem_ptr as *mut usize);
-
-    Ok(())
+    runtime.run(program, &tokens.spans)
 }
-
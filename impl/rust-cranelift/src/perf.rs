@@ -0,0 +1,217 @@
+use brainfetch_core::command::SourcePos;
+use brainfetch_core::command_opt::{CommandOpt, Program};
+use brainfetch_core::Error;
+use crate::jit::CodeMap;
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Stretch of compiled code that perf should show under one name
+pub struct Symbol {
+    /// Address of the first byte
+    pub start: usize,
+    pub size: usize,
+    pub name: String,
+    /// Address of each instruction's code in the symbol, with the source line it came from
+    pub lines: Vec<(usize, usize)>,
+}
+
+/// Name the compiled code of `program` after the innermost loop each part of it belongs to,
+/// like `loop@12:5` for a loop whose `[` is on line 12, column 5 of `code`. Code outside any
+/// loop is named `root`. A loop's code can be split over several symbols, since the compiler
+/// moves rarely taken paths to the end of the function.
+pub fn symbols(code_map: &CodeMap, program: &Program, code: &str, root: &str) -> Vec<Symbol> {
+    // Innermost loop around each instruction, given by its `OpenBr`. The brackets belong to the
+    // loop they delimit.
+    let mut open_loops = Vec::new();
+    let innermost: Vec<Option<usize>> = program
+        .commands
+        .iter()
+        .enumerate()
+        .map(|(index, cmd)| match cmd {
+            CommandOpt::OpenBr(_) => {
+                open_loops.push(index);
+                Some(index)
+            }
+            CommandOpt::CloseBr(_) => open_loops.pop(),
+            _ => open_loops.last().copied(),
+        })
+        .collect();
+
+    // Offset of the start of each line, to look up many instructions' lines quickly
+    let line_starts: Vec<usize> =
+        std::iter::once(0).chain(code.match_indices('\n').map(|(offset, _)| offset + 1)).collect();
+
+    let base = code_map.start as usize;
+    let mut symbols: Vec<(Option<usize>, Symbol)> = Vec::new();
+    for (range, index) in &code_map.ranges {
+        let owner = index.and_then(|index| innermost[index]);
+        match symbols.last_mut() {
+            Some((last_owner, symbol)) if *last_owner == owner => {}
+            last => {
+                // Code between the tagged ranges, like register moves, goes to what came before
+                if let Some((_, symbol)) = last {
+                    symbol.size = base + range.start - symbol.start;
+                }
+                let name = match owner {
+                    Some(open) => {
+                        let pos = SourcePos::find(code, program.spans[open].start);
+                        format!("loop@{}:{}", pos.line, pos.column)
+                    }
+                    None => String::from(root),
+                };
+                // The first symbol also covers the prologue
+                let start = if symbols.is_empty() { 0 } else { range.start };
+                let symbol = Symbol { start: base + start, size: 0, name, lines: Vec::new() };
+                symbols.push((owner, symbol));
+            }
+        }
+        let symbol = &mut symbols.last_mut().expect("pushed above if there were none").1;
+        symbol.size = base + range.end - symbol.start;
+        if let Some(index) = index {
+            let offset = program.spans[*index].start;
+            let line = line_starts.partition_point(|&start| start <= offset);
+            symbol.lines.push((base + range.start, line));
+        }
+    }
+    match symbols.last_mut() {
+        Some((_, symbol)) => symbol.size = base + code_map.size - symbol.start,
+        None => {
+            let (name, lines) = (String::from(root), Vec::new());
+            symbols.push((None, Symbol { start: base, size: code_map.size, name, lines }));
+        }
+    }
+    symbols.into_iter().map(|(_, symbol)| symbol).collect()
+}
+
+/// Add `symbols` to `/tmp/perf-<PID>.map`, where `perf report` looks up names for code that
+/// isn't backed by a file
+pub fn write_perf_map(symbols: &[Symbol]) -> Result<PathBuf, Error> {
+    let path = PathBuf::from(format!("/tmp/perf-{}.map", std::process::id()));
+    let write = || -> std::io::Result<()> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let mut file = BufWriter::new(file);
+        for symbol in symbols {
+            writeln!(file, "{:x} {:x} {}", symbol.start, symbol.size, symbol.name)?;
+        }
+        file.flush()
+    };
+    write().map_err(|source| Error::Write { path: path.clone(), source })?;
+    Ok(path)
+}
+
+// Record types and constants of the jitdump format, see
+// https://github.com/torvalds/linux/blob/master/tools/perf/Documentation/jitdump-specification.txt
+const JITDUMP_MAGIC: u32 = 0x4A695444;
+const JITDUMP_VERSION: u32 = 1;
+const JIT_CODE_LOAD: u32 = 0;
+const JIT_CODE_DEBUG_INFO: u32 = 2;
+
+/// Write `symbols` to `jit-<PID>.dump` in `dir`, with the source line of every instruction of
+/// `source_path`. `perf inject --jit` then turns it into ELF files that `perf report` and
+/// `perf annotate` can show the machine code from. Record with `perf record -k mono`, since the
+/// timestamps come from the monotonic clock.
+pub fn write_jitdump(dir: &Path, symbols: &[Symbol], source_path: &Path) -> Result<PathBuf, Error> {
+    let pid = std::process::id();
+    let path = dir.join(format!("jit-{}.dump", pid));
+    let source_path = std::path::absolute(source_path).unwrap_or_else(|_| source_path.into());
+    let source_name = source_path.to_string_lossy();
+    let write = || -> std::io::Result<()> {
+        // Opened for reading too, which mapping it requires
+        let file =
+            OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path)?;
+        // perf only picks up the dump if the process maps it executable, which shows up as an
+        // mmap event in the recording. The mapping isn't needed for anything else, so it's
+        // never unmapped.
+        let mapping = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                page_size(),
+                libc::PROT_READ | libc::PROT_EXEC,
+                libc::MAP_PRIVATE,
+                std::os::fd::AsRawFd::as_raw_fd(&file),
+                0,
+            )
+        };
+        if mapping == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let mut file = BufWriter::new(file);
+        let header_size = 40;
+        file.write_all(&JITDUMP_MAGIC.to_ne_bytes())?;
+        file.write_all(&JITDUMP_VERSION.to_ne_bytes())?;
+        file.write_all(&(header_size as u32).to_ne_bytes())?;
+        file.write_all(&elf_machine().to_ne_bytes())?;
+        file.write_all(&0u32.to_ne_bytes())?; // padding
+        file.write_all(&pid.to_ne_bytes())?;
+        file.write_all(&timestamp().to_ne_bytes())?;
+        file.write_all(&0u64.to_ne_bytes())?; // flags
+
+        for (code_index, symbol) in symbols.iter().enumerate() {
+            // Line numbers have to come before the code they describe
+            if !symbol.lines.is_empty() {
+                let entry_size = 16 + source_name.len() + 1;
+                let size = 16 + 16 + symbol.lines.len() * entry_size;
+                write_record_header(&mut file, JIT_CODE_DEBUG_INFO, size)?;
+                file.write_all(&(symbol.start as u64).to_ne_bytes())?;
+                file.write_all(&(symbol.lines.len() as u64).to_ne_bytes())?;
+                for &(address, line) in &symbol.lines {
+                    file.write_all(&(address as u64).to_ne_bytes())?;
+                    file.write_all(&(line as u32).to_ne_bytes())?;
+                    file.write_all(&0u32.to_ne_bytes())?; // discriminator
+                    file.write_all(source_name.as_bytes())?;
+                    file.write_all(&[0])?;
+                }
+            }
+
+            let size = 16 + 40 + symbol.name.len() + 1 + symbol.size;
+            write_record_header(&mut file, JIT_CODE_LOAD, size)?;
+            file.write_all(&pid.to_ne_bytes())?;
+            file.write_all(&pid.to_ne_bytes())?; // thread, which is the main one
+            file.write_all(&(symbol.start as u64).to_ne_bytes())?; // virtual address
+            file.write_all(&(symbol.start as u64).to_ne_bytes())?; // code address
+            file.write_all(&(symbol.size as u64).to_ne_bytes())?;
+            file.write_all(&(code_index as u64).to_ne_bytes())?;
+            file.write_all(symbol.name.as_bytes())?;
+            file.write_all(&[0])?;
+            // The compiled code is mapped readable, and stays alive as long as the process
+            let code =
+                unsafe { std::slice::from_raw_parts(symbol.start as *const u8, symbol.size) };
+            file.write_all(code)?;
+        }
+        file.flush()
+    };
+    write().map_err(|source| Error::Write { path: path.clone(), source })?;
+    Ok(path)
+}
+
+fn write_record_header(file: &mut impl Write, id: u32, size: usize) -> std::io::Result<()> {
+    file.write_all(&id.to_ne_bytes())?;
+    file.write_all(&(size as u32).to_ne_bytes())?;
+    file.write_all(&timestamp().to_ne_bytes())
+}
+
+/// Current time on the clock `perf record -k mono` uses, in nanoseconds
+fn timestamp() -> u64 {
+    let mut time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time) };
+    time.tv_sec as u64 * 1_000_000_000 + time.tv_nsec as u64
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// ELF machine type of the host, which perf uses to disassemble the code
+fn elf_machine() -> u32 {
+    match std::env::consts::ARCH {
+        "x86" => 3,
+        "x86_64" => 62,
+        "arm" => 40,
+        "aarch64" => 183,
+        "riscv64" => 243,
+        "s390x" => 22,
+        _ => 0,
+    }
+}
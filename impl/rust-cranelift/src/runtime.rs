@@ -0,0 +1,181 @@
+use brainfetch_core::io::{Input, Output};
+use brainfetch_core::limits::{Interrupt, Limits, Meter};
+use brainfetch_core::{Error, Fault};
+use std::io::{Read, Write};
+use std::ops::Range;
+use crate::jit::{JitFn, STATUS_INPUT_ERROR, STATUS_INVALID_CHAR, STATUS_OK, STATUS_OUTPUT_ERROR, STATUS_OUT_OF_FUEL, STATUS_TIMEOUT, STATUS_UNDERFLOW};
+
+/// Host-side state of a running JIT program. Compiled code gets a pointer to this and reads the
+/// `#[repr(C)]` fields directly, calling back into the host functions below for everything else,
+/// including I/O on whatever streams the runtime was set up with.
+#[repr(C)]
+pub struct Runtime<'io> {
+    /// Start of the tape. Only valid until the next `grow_tape` call.
+    pub cells: *mut u8,
+    /// Number of cells currently allocated
+    pub len: usize,
+    /// Read/write head. Only up-to-date once the compiled function returns.
+    pub head: usize,
+    /// Index of the instruction that stopped the program, if it failed
+    pub error_at: usize,
+    /// Instructions a metered program may execute before calling `refuel`. Negative once it
+    /// went past that.
+    pub fuel: i64,
+    /// Index of the instruction a metered program continues at: 0 to start from the beginning,
+    /// or where it was interrupted
+    pub resume_at: usize,
+    meter: Meter,
+    cell_bytes: usize,
+    /// Backing storage for the tape, in words so wider cells are aligned
+    memory: Vec<u64>,
+    input: Input<Box<dyn Read + 'io>>,
+    output: Output<Box<dyn Write + 'io>>,
+    /// Error behind the last `STATUS_INPUT_ERROR` or `STATUS_OUTPUT_ERROR`
+    pub io_error: Option<std::io::Error>,
+}
+
+impl<'io> Runtime<'io> {
+    /// Set up a tape of at least `initial_len` cells that are `cell_bits` wide, for a program
+    /// reading from `input` and writing to `output`
+    pub fn new(
+        initial_len: usize,
+        cell_bits: u32,
+        input: Input<impl Read + 'io>,
+        output: Output<impl Write + 'io>,
+    ) -> Self {
+        let mut runtime = Self {
+            cells: std::ptr::null_mut(),
+            len: 0,
+            head: 0,
+            error_at: 0,
+            fuel: 0,
+            resume_at: 0,
+            meter: Meter::new(&Limits::default()),
+            cell_bytes: cell_bits as usize / 8,
+            memory: Vec::new(),
+            input: input.boxed(),
+            output: output.boxed(),
+            io_error: None,
+        };
+        runtime.resize(initial_len);
+        runtime
+    }
+
+    /// Make room for at least `len` cells
+    fn resize(&mut self, len: usize) {
+        let cells_per_word = 8 / self.cell_bytes;
+        self.memory.resize(len.div_ceil(cells_per_word), 0);
+        self.cells = self.memory.as_mut_ptr() as *mut u8;
+        self.len = self.memory.len() * cells_per_word;
+    }
+
+    /// Limit how long the next calls of a metered program may run. Fuel left over from earlier
+    /// limits is dropped.
+    pub fn set_limits(&mut self, limits: &Limits) {
+        self.meter = Meter::new(limits);
+        self.fuel = self.fuel.min(0);
+    }
+
+    /// Call the compiled `program` and turn the status it returns into a result. `spans` are the
+    /// source ranges of the program's instructions, to point errors back at.
+    ///
+    /// If the program was interrupted, calling this again continues where it stopped.
+    pub fn run(&mut self, program: JitFn, spans: &[Range<usize>]) -> Result<(), Error> {
+        let status = program(self);
+        let (index, head) = (self.error_at, self.head);
+        let fault = match status {
+            STATUS_OK => {
+                self.resume_at = 0;
+                return self.flush().map_err(Error::Output);
+            }
+            STATUS_OUTPUT_ERROR => return Err(Error::Output(self.io_error.take().unwrap())),
+            STATUS_INPUT_ERROR => return Err(Error::Input(self.io_error.take().unwrap())),
+            STATUS_OUT_OF_FUEL | STATUS_TIMEOUT => {
+                let interrupt = match status {
+                    STATUS_OUT_OF_FUEL => Interrupt::OutOfFuel,
+                    _ => Interrupt::Timeout,
+                };
+                self.resume_at = index;
+                self.flush().map_err(Error::Output)?;
+                let span = spans[index].clone();
+                return Err(Error::Interrupted { interrupt, index, span, head });
+            }
+            STATUS_UNDERFLOW => Fault::PointerUnderflow,
+            STATUS_INVALID_CHAR => Fault::InvalidChar,
+            _ => Fault::CellOverflow,
+        };
+        let span = spans[index].clone();
+        Err(Error::Runtime { fault, index, span, head })
+    }
+
+    /// Write out any output still buffered once the compiled function returned
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/// Called by compiled code when it accesses a cell past the end of the tape. Reallocates the tape
+/// so that `index` is in bounds; the caller must reload `cells` and `len` afterwards.
+pub extern "C" fn grow_tape(runtime: *mut Runtime, index: usize) {
+    let runtime = unsafe { &mut *runtime };
+    runtime.resize((index + 1).max(runtime.len * 2)); // Dynamically growing memory
+}
+
+/// Called by compiled code at a loop once `fuel` went negative. Tops it up from the runtime's
+/// limits, or tells the compiled function to stop if it hit one.
+pub extern "C" fn refuel(runtime: *mut Runtime) -> u8 {
+    let runtime = unsafe { &mut *runtime };
+    while runtime.fuel < 0 {
+        match runtime.meter.refill() {
+            Ok(slice) => {
+                let slice = i64::try_from(slice).unwrap_or(i64::MAX);
+                runtime.fuel = runtime.fuel.saturating_add(slice);
+            }
+            Err(Interrupt::OutOfFuel) => return STATUS_OUT_OF_FUEL,
+            Err(Interrupt::Timeout) => return STATUS_TIMEOUT,
+        }
+    }
+    STATUS_OK
+}
+
+pub extern "C" fn put_char(runtime: *mut Runtime, value: u64) -> u8 {
+    let runtime = unsafe { &mut *runtime };
+    match runtime.output.put(value) {
+        Ok(()) => STATUS_OK,
+        Err(err) if err.kind() == std::io::ErrorKind::InvalidData => STATUS_INVALID_CHAR,
+        Err(err) => {
+            runtime.io_error = Some(err);
+            STATUS_OUTPUT_ERROR
+        }
+    }
+}
+
+/// Reads the next input byte straight into `cell`, which is left alone on EOF if the runtime's
+/// input is set up that way
+pub extern "C" fn get_char(runtime: *mut Runtime, cell: *mut u8) -> u8 {
+    let runtime = unsafe { &mut *runtime };
+    // Make sure any prompt is visible before blocking on input
+    if let Err(err) = runtime.output.flush() {
+        runtime.io_error = Some(err);
+        return STATUS_OUTPUT_ERROR;
+    }
+    match runtime.input.get() {
+        Ok(Some(value)) => {
+            // Truncate to the cell width. Cells are always aligned, see `Runtime::memory`.
+            unsafe {
+                match runtime.cell_bytes {
+                    1 => *cell = value as u8,
+                    2 => *(cell as *mut u16) = value as u16,
+                    4 => *(cell as *mut u32) = value as u32,
+                    _ => *(cell as *mut u64) = value,
+                }
+            }
+            STATUS_OK
+        }
+        Ok(None) => STATUS_OK,
+        Err(err) => {
+            runtime.io_error = Some(err);
+            STATUS_INPUT_ERROR
+        }
+    }
+}
@@ -0,0 +1,373 @@
+use std::fmt::Write;
+use std::fs;
+use std::path::PathBuf;
+use clap::{Args, ValueEnum};
+use brainfetch_core::command::SourcePos;
+use brainfetch_core::command_opt::{self, CommandOpt, OverflowMode, Program};
+use brainfetch_core::io::{EofBehavior, OutputEncoding};
+use brainfetch_core::{Error, Fault};
+
+#[derive(Args)]
+pub struct CompileArgs {
+    /// BrainF*** file to compile
+    pub file: PathBuf,
+
+    /// What to compile the program to
+    #[arg(long, value_enum, default_value_t)]
+    emit: Emit,
+
+    /// File to write, by default the source file with the extension of what's emitted
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<PathBuf>,
+
+    /// Number of cells on the tape. Unlike the interpreter's, it doesn't grow, so the compiled
+    /// program stops with an error if the head moves past the end.
+    #[arg(
+        long,
+        value_name = "CELLS",
+        default_value_t = 30_000,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    tape_size: u64,
+
+    #[command(flatten)]
+    options: crate::Options,
+}
+
+#[derive(Clone, Copy, Default, ValueEnum)]
+enum Emit {
+    /// Standalone C99 source, for any C compiler
+    #[default]
+    C,
+}
+
+/// Compile the program in `code` as `args` ask for, and write it out
+pub fn run(args: &CompileArgs, code: &str) -> Result<(), Error> {
+    let options = &args.options;
+    let program = command_opt::parse(code, options.cell_bits, options.overflow)
+        .map_err(Error::Parse)?;
+    let (compiled, extension) = match args.emit {
+        Emit::C => (emit_c(&program, code, args), "c"),
+    };
+    let path = args.output.clone().unwrap_or_else(|| args.file.with_extension(extension));
+    fs::write(&path, compiled).map_err(|source| Error::Write { path, source })
+}
+
+/// Lower `program`, generated from `code`, to a C program that behaves like the interpreter
+/// with the same options, except that the tape has a fixed size. Faults are reported the same
+/// way and with the same exit codes, pointing at the line and column of the instruction.
+fn emit_c(program: &Program, code: &str, args: &CompileArgs) -> String {
+    let options = &args.options;
+    let name = args.file.file_name().unwrap_or(args.file.as_os_str()).to_string_lossy();
+    let mut c = String::new();
+    writeln!(c, "/* Compiled from {} by brainfetch */", name.replace("*/", "*_/")).unwrap();
+    c.push_str(C_PRELUDE);
+    // Same messages as the interpreter's faults
+    writeln!(c).unwrap();
+    for (name, fault) in [
+        ("UNDERFLOW", Fault::PointerUnderflow),
+        ("OVERFLOW", Fault::CellOverflow),
+        ("INVALID_CHAR", Fault::InvalidChar),
+    ] {
+        writeln!(c, "#define {} \"{}\"", name, fault).unwrap();
+    }
+    c.push_str(
+        "#define PAST_END \"Pointer overflow (attempted to move read/write head past the end of \
+         the tape)\"\n",
+    );
+    writeln!(c, "#define TAPE_SIZE {}", args.tape_size).unwrap();
+    let bits = options.cell_bits;
+    writeln!(c, "typedef uint{0}_t cell;\n#define CELL_MAX UINT{0}_MAX", bits).unwrap();
+
+    c.push_str("\nstatic cell tape[TAPE_SIZE];\nstatic size_t head;\n");
+
+    let cell_max = u64::MAX >> (64 - options.cell_bits);
+    // Only the helpers the program uses, so the C compiler doesn't warn about the others
+    let uses = |used: fn(&CommandOpt) -> bool| program.commands.iter().any(used);
+    let uses_mul_add = uses(|cmd| matches!(cmd, CommandOpt::MulAdd { .. }));
+    let uses_add = uses(|cmd| matches!(cmd, CommandOpt::ChVal { .. })) || uses_mul_add;
+    let uses_put = uses(|cmd| matches!(cmd, CommandOpt::PutChar { .. }));
+    let uses_at = uses(|cmd| match *cmd {
+        CommandOpt::ChPtr(_) | CommandOpt::Scan(_) => true,
+        CommandOpt::ChVal { offset, .. }
+        | CommandOpt::PutChar { offset }
+        | CommandOpt::GetChar { offset }
+        | CommandOpt::Zero { offset }
+        | CommandOpt::MulAdd { offset, .. } => offset != 0,
+        _ => false,
+    });
+    // Every 8-bit value is a Latin-1 character, and checking anyway makes compilers warn
+    let checks_put = match options.output_encoding {
+        OutputEncoding::Raw => false,
+        OutputEncoding::Utf8 => true,
+        OutputEncoding::Latin1 => options.cell_bits > 8,
+    };
+    let can_fault = uses_at
+        || (uses_add && options.overflow == OverflowMode::Check)
+        || (uses_put && checks_put);
+    if can_fault {
+        writeln!(c, "\n/* Source line and column of each instruction, for error messages */")
+            .unwrap();
+        writeln!(c, "static const unsigned long positions[][2] = {{").unwrap();
+        for span in &program.spans {
+            let pos = SourcePos::find(code, span.start);
+            writeln!(c, "    {{{}, {}}},", pos.line, pos.column).unwrap();
+        }
+        c.push_str("};\n");
+        c.push_str(C_FAULT);
+    }
+    if uses_at {
+        c.push_str(C_AT);
+    }
+    if uses_add {
+        c.push_str(match options.overflow {
+            OverflowMode::Wrap => "",
+            OverflowMode::Check => C_ADD_CHECKED,
+            OverflowMode::Saturate => C_ADD_SATURATING,
+        });
+    }
+    if uses_mul_add {
+        c.push_str(match options.overflow {
+            OverflowMode::Wrap => "",
+            OverflowMode::Check => C_MUL_ADD_CHECKED,
+            OverflowMode::Saturate => C_MUL_ADD_SATURATING,
+        });
+    }
+    if uses_put {
+        match options.output_encoding {
+            OutputEncoding::Raw => c.push_str(C_PUT_RAW),
+            OutputEncoding::Utf8 => c.push_str(C_PUT_UTF8),
+            OutputEncoding::Latin1 => {
+                let check = if checks_put { C_LATIN1_CHECK } else { "(void)index;" };
+                c.push_str(&C_PUT_LATIN1.replace("CHECK", check));
+            }
+        }
+    }
+    if uses(|cmd| matches!(cmd, CommandOpt::GetChar { .. })) {
+        let at_eof = match options.eof {
+            EofBehavior::Zero => "*value = 0;",
+            EofBehavior::Max => "*value = CELL_MAX;",
+            EofBehavior::Unchanged => "return;",
+        };
+        let on_error = match options.strict_input {
+            true => "fprintf(stderr, \"Error: Unable to read input\\n\");\n            exit(5);",
+            false => "/* Treated like the end of input */",
+        };
+        c.push_str(&C_GET.replace("ON_ERROR", on_error).replace("AT_EOF", at_eof));
+    }
+
+    // The head is always on the tape, so only other cells need checking
+    let cell = |offset: isize, index: usize| match offset {
+        0 => String::from("tape[head]"),
+        _ => format!("tape[at({}, {})]", offset, index),
+    };
+    c.push_str("\nint main(void) {\n");
+    let mut depth = 1;
+    for (index, cmd) in program.commands.iter().enumerate() {
+        if let CommandOpt::CloseBr(_) = cmd {
+            depth -= 1;
+        }
+        let indent = "    ".repeat(depth);
+        let statement = match *cmd {
+            CommandOpt::ChPtr(amount) => format!("head = at({}, {});", amount, index),
+            CommandOpt::ChVal { offset, amount } => {
+                let cell = cell(offset, index);
+                match options.overflow {
+                    // Wrapping the amount to the cell width first keeps the literal small
+                    OverflowMode::Wrap => {
+                        let amount = amount as u64 & cell_max;
+                        format!("{0} = (cell)({0} + {1}u);", cell, amount)
+                    }
+                    _ => format!(
+                        "{0} = add({0}, {1}u, {2}, {3});",
+                        cell,
+                        amount.unsigned_abs(),
+                        (amount > 0) as u8,
+                        index
+                    ),
+                }
+            }
+            CommandOpt::PutChar { offset } => format!("put({}, {});", cell(offset, index), index),
+            CommandOpt::GetChar { offset } => format!("get(&{});", cell(offset, index)),
+            CommandOpt::Zero { offset } => format!("{} = 0;", cell(offset, index)),
+            CommandOpt::MulAdd { offset, factor } => {
+                let cell = cell(offset, index);
+                match options.overflow {
+                    // The target is only touched if the current cell isn't zero, like in the
+                    // interpreter. Multiplied as 64 bits, since narrower cells would be promoted
+                    // to a signed int, which mustn't overflow.
+                    OverflowMode::Wrap => {
+                        let factor = factor as u64 & cell_max;
+                        format!(
+                            "if (tape[head] != 0) {0} = (cell)({0} + (uint64_t)tape[head] * {1}u);",
+                            cell, factor
+                        )
+                    }
+                    _ => format!(
+                        "if (tape[head] != 0) {0} = mul_add({0}, tape[head], {1}u, {2}, {3});",
+                        cell,
+                        factor.unsigned_abs(),
+                        (factor > 0) as u8,
+                        index
+                    ),
+                }
+            }
+            CommandOpt::Scan(stride) => {
+                format!("while (tape[head] != 0) head = at({}, {});", stride, index)
+            }
+            // Nothing can change the cell, so the program hangs like the interpreter would
+            CommandOpt::LoopForever => String::from("if (tape[head] != 0) for (;;) {}"),
+            CommandOpt::OpenBr(_) => String::from("while (tape[head] != 0) {"),
+            CommandOpt::CloseBr(_) => String::from("}"),
+        };
+        writeln!(c, "{}{}", indent, statement).unwrap();
+        if let CommandOpt::OpenBr(_) = cmd {
+            depth += 1;
+        }
+    }
+    c.push_str(C_EPILOGUE);
+    c
+}
+
+const C_PRELUDE: &str = "
+#include <stddef.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+";
+
+const C_FAULT: &str = "
+/* Report a fault at instruction `index` the way the interpreter does, and exit */
+static void fault(const char *message, size_t index) {
+    fflush(stdout);
+    fprintf(stderr, \"Error: %s at instruction %zu (read/write head at cell %zu)\\n\",
+            message, index, head);
+    fprintf(stderr, \"At line %lu, column %lu\\n\", positions[index][0], positions[index][1]);
+    exit(4);
+}
+";
+
+const C_AT: &str = "
+/* Index of the cell `offset` cells from the head, which has to be on the tape. Moving left of 0
+   wraps around to an index past the end, so one comparison catches both ends. */
+static size_t at(ptrdiff_t offset, size_t index) {
+    size_t cell_index = head + (size_t)offset;
+    if (cell_index >= TAPE_SIZE) {
+        fault(offset < 0 ? UNDERFLOW : PAST_END, index);
+    }
+    return cell_index;
+}
+";
+
+const C_ADD_CHECKED: &str = "
+/* Add `amount` to `value`, or subtract it unless `up`, stopping on overflow */
+static cell add(cell value, uint64_t amount, int up, size_t index) {
+    if (up ? amount > (uint64_t)CELL_MAX - value : amount > value) {
+        fault(OVERFLOW, index);
+    }
+    return up ? (cell)(value + amount) : (cell)(value - amount);
+}
+";
+
+const C_MUL_ADD_CHECKED: &str = "
+/* Add `src * factor` to `target`, or subtract it unless `up`, stopping on overflow. `src` isn't
+   zero, since the target cell is only touched if it isn't. */
+static cell mul_add(cell target, cell src, uint64_t factor, int up, size_t index) {
+    if (factor > (uint64_t)CELL_MAX / src) {
+        fault(OVERFLOW, index);
+    }
+    return add(target, src * factor, up, index);
+}
+";
+
+const C_ADD_SATURATING: &str = "
+/* Add `amount` to `value`, or subtract it unless `up`, clamping the result to the cell's range */
+static cell add(cell value, uint64_t amount, int up, size_t index) {
+    (void)index;
+    if (up) {
+        return amount > (uint64_t)CELL_MAX - value ? CELL_MAX : (cell)(value + amount);
+    }
+    return amount > value ? 0 : (cell)(value - amount);
+}
+";
+
+const C_MUL_ADD_SATURATING: &str = "
+/* Add `src * factor` to `target`, or subtract it unless `up`, clamping the result. `src` isn't
+   zero, since the target cell is only touched if it isn't. */
+static cell mul_add(cell target, cell src, uint64_t factor, int up, size_t index) {
+    if (factor > (uint64_t)CELL_MAX / src) {
+        return up ? CELL_MAX : 0;
+    }
+    return add(target, src * factor, up, index);
+}
+";
+
+const C_GET: &str = "
+/* Read a byte into `*value`, or apply the EOF behavior if there is none */
+static void get(cell *value) {
+    int ch;
+    fflush(stdout);
+    ch = getchar();
+    if (ch == EOF) {
+        if (ferror(stdin)) {
+            ON_ERROR
+        }
+        AT_EOF
+        return;
+    }
+    *value = (cell)ch;
+}
+";
+
+const C_PUT_RAW: &str = "
+/* Write the low byte of `value` */
+static void put(cell value, size_t index) {
+    (void)index;
+    putchar((unsigned char)value);
+}
+";
+
+const C_PUT_UTF8: &str = "
+/* Write `value` as a UTF-8 encoded code point */
+static void put(cell value, size_t index) {
+    uint64_t ch = value;
+    if (ch > 0x10FFFF || (ch >= 0xD800 && ch <= 0xDFFF)) {
+        fault(INVALID_CHAR, index);
+    }
+    if (ch < 0x80) {
+        putchar((int)ch);
+    } else if (ch < 0x800) {
+        putchar((int)(0xC0 | ch >> 6));
+        putchar((int)(0x80 | (ch & 0x3F)));
+    } else if (ch < 0x10000) {
+        putchar((int)(0xE0 | ch >> 12));
+        putchar((int)(0x80 | (ch >> 6 & 0x3F)));
+        putchar((int)(0x80 | (ch & 0x3F)));
+    } else {
+        putchar((int)(0xF0 | ch >> 18));
+        putchar((int)(0x80 | (ch >> 12 & 0x3F)));
+        putchar((int)(0x80 | (ch >> 6 & 0x3F)));
+        putchar((int)(0x80 | (ch & 0x3F)));
+    }
+}
+";
+
+const C_PUT_LATIN1: &str = "
+/* Write `value` as a single byte, which it has to fit in */
+static void put(cell value, size_t index) {
+    CHECK
+    putchar((int)value);
+}
+";
+
+const C_LATIN1_CHECK: &str = "if (value > 0xFF) {
+        fault(INVALID_CHAR, index);
+    }";
+
+const C_EPILOGUE: &str = "    if (fflush(stdout) != 0 || ferror(stdout)) {
+        fprintf(stderr, \"Error: Unable to write output\\n\");
+        return 5;
+    }
+    return 0;
+}
+";
@@ -0,0 +1,757 @@
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use clap::Args;
+use serde_json::{json, Value};
+use brainfetch_core::cell::Cell;
+use brainfetch_core::command::SourcePos;
+use brainfetch_core::debug::{Debugger, Pause, DEFAULT_JOURNAL_LIMIT};
+use brainfetch_core::{io, Error};
+use crate::debugger::open_input;
+
+#[derive(Args)]
+pub struct DapArgs {
+    /// Number of steps to remember for stepping backwards. Each one takes up 64 bytes.
+    #[arg(long, value_name = "STEPS", default_value_t = DEFAULT_JOURNAL_LIMIT)]
+    journal_limit: usize,
+
+    #[command(flatten)]
+    options: crate::Options,
+}
+
+/// Commands run between checks for a `pause` request while the program is running
+const CHUNK: u64 = 1 << 16;
+
+/// Variable references of the scopes every frame has
+const TAPE_SCOPE: u64 = 1;
+const MACHINE_SCOPE: u64 = 2;
+
+/// Speak the Debug Adapter Protocol over stdin and stdout. The program to debug and its input
+/// come from the `launch` request, whose arguments are `program`, `input`, `stopOnEntry` and
+/// `ignoreHash`, named like their command line counterparts.
+pub fn run(args: &DapArgs) -> Result<(), Error> {
+    let incoming = spawn_reader(BufReader::new(std::io::stdin()));
+    let writer = Box::new(std::io::stdout());
+    match args.options.cell_bits {
+        8 => Server::<u8>::new(args, incoming, writer).serve(),
+        16 => Server::<u16>::new(args, incoming, writer).serve(),
+        32 => Server::<u32>::new(args, incoming, writer).serve(),
+        _ => Server::<u64>::new(args, incoming, writer).serve(),
+    }
+}
+
+/// Read messages from `reader` on a separate thread, so a running program can check for a
+/// `pause` request without blocking. The channel closes when the client goes away or sends
+/// garbage.
+fn spawn_reader(mut reader: impl BufRead + Send + 'static) -> Receiver<Value> {
+    let (sender, incoming) = mpsc::channel();
+    thread::spawn(move || {
+        while let Ok(Some(message)) = read_message(&mut reader) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+    incoming
+}
+
+/// Read one `Content-Length` framed message, or `None` at the end of the stream
+fn read_message(reader: &mut impl BufRead) -> std::io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok();
+        }
+    }
+    let length = length.ok_or(std::io::ErrorKind::InvalidData)?;
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+/// How to move through the program after replying to a request
+enum Motion {
+    Continue,
+    /// Step over an optimized instruction
+    Next,
+    /// Step into a single command
+    StepIn,
+    /// Run until leaving this range of instruction indices
+    StepOut(std::ops::Range<usize>),
+    StepBack,
+    ReverseContinue,
+}
+
+/// What to do once a request has been replied to
+enum Then {
+    Nothing,
+    Run(Motion),
+    /// Report the program as stopped, giving the reason
+    Stop(&'static str),
+    Quit,
+}
+
+/// A launched program
+struct Session<C: Cell> {
+    path: PathBuf,
+    code: String,
+    debugger: Debugger<C>,
+    input: io::Input<Box<dyn Read>>,
+    /// The program's output, sent to the client as output events
+    output: io::Output<Vec<u8>>,
+    stop_on_entry: bool,
+    ignore_hash: bool,
+}
+
+struct Server<'a, C: Cell> {
+    args: &'a DapArgs,
+    incoming: Receiver<Value>,
+    /// Where responses and events go
+    writer: Box<dyn Write + 'a>,
+    /// Requests that came in while the program was running, to handle once it stops
+    pending: VecDeque<Value>,
+    seq: u64,
+    session: Option<Session<C>>,
+    /// Whether the client counts lines and columns from 1 (the default) or 0
+    line_base: usize,
+    column_base: usize,
+}
+
+impl<'a, C: Cell> Server<'a, C> {
+    fn new(args: &'a DapArgs, incoming: Receiver<Value>, writer: Box<dyn Write + 'a>) -> Self {
+        Self {
+            args,
+            incoming,
+            writer,
+            pending: VecDeque::new(),
+            seq: 0,
+            session: None,
+            line_base: 1,
+            column_base: 1,
+        }
+    }
+
+    fn serve(&mut self) -> Result<(), Error> {
+        loop {
+            let request = match self.pending.pop_front() {
+                Some(request) => request,
+                None => match self.incoming.recv() {
+                    Ok(request) => request,
+                    Err(_) => return Ok(()),
+                },
+            };
+            if request["type"] != "request" {
+                continue;
+            }
+            let command = request["command"].as_str().unwrap_or_default();
+            let (body, then) = match self.handle(command, &request["arguments"]) {
+                Ok((body, then)) => (Ok(body), then),
+                Err(message) => (Err(message), Then::Nothing),
+            };
+            self.respond(&request, body)?;
+            match then {
+                Then::Nothing => {}
+                Then::Run(motion) => self.run(motion)?,
+                Then::Stop(reason) => self.stopped(reason, None)?,
+                Then::Quit => return Ok(()),
+            }
+        }
+    }
+
+    /// Carry out one request, returning the body of the response or why it failed
+    fn handle(&mut self, command: &str, args: &Value) -> Result<(Value, Then), String> {
+        match command {
+            "initialize" => {
+                if args["linesStartAt1"] == false {
+                    self.line_base = 0;
+                }
+                if args["columnsStartAt1"] == false {
+                    self.column_base = 0;
+                }
+                let capabilities = json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsStepBack": true,
+                    "supportsSetVariable": true,
+                    "supportsDataBreakpoints": true,
+                    "supportsTerminateRequest": true,
+                });
+                return Ok((capabilities, Then::Nothing));
+            }
+            "launch" => {
+                self.launch(args)?;
+                // Ready for breakpoints now that the source is known
+                self.send(json!({ "type": "event", "event": "initialized" }))
+                    .map_err(|err| err.to_string())?;
+                return Ok((Value::Null, Then::Nothing));
+            }
+            "disconnect" | "terminate" => return Ok((Value::Null, Then::Quit)),
+            "threads" => {
+                return Ok((json!({ "threads": [{ "id": 1, "name": "main" }] }), Then::Nothing));
+            }
+            _ => {}
+        }
+
+        let session = self.session.as_mut().ok_or("No program launched")?;
+        let debugger = &mut session.debugger;
+        let then = match command {
+            "configurationDone" => match session.stop_on_entry {
+                true => Then::Stop("entry"),
+                false => Then::Run(Motion::Continue),
+            },
+            "setBreakpoints" => return Ok((self.set_breakpoints(args), Then::Nothing)),
+            "stackTrace" => return Ok((self.stack_trace(), Then::Nothing)),
+            "scopes" => {
+                let scopes = json!({ "scopes": [
+                    {
+                        "name": "Tape",
+                        "variablesReference": TAPE_SCOPE,
+                        "indexedVariables": debugger.machine.mem.len(),
+                        "expensive": false,
+                    },
+                    {
+                        "name": "Machine",
+                        "variablesReference": MACHINE_SCOPE,
+                        "namedVariables": 4,
+                        "expensive": false,
+                    },
+                ]});
+                return Ok((scopes, Then::Nothing));
+            }
+            "variables" => return Ok((self.variables(args), Then::Nothing)),
+            "setVariable" => return self.set_variable(args).map(|body| (body, Then::Nothing)),
+            "dataBreakpointInfo" => {
+                let info = match (args["variablesReference"].as_u64(), parse_cell(&args["name"])) {
+                    (Some(TAPE_SCOPE), Some(cell)) => json!({
+                        "dataId": format!("cell:{}", cell),
+                        "description": format!("Cell {}", cell),
+                        "accessTypes": ["write"],
+                    }),
+                    _ => json!({ "dataId": null, "description": "Only tape cells can be watched" }),
+                };
+                return Ok((info, Then::Nothing));
+            }
+            "setDataBreakpoints" => {
+                let watched: Vec<usize> = debugger.watchpoints().collect();
+                for cell in watched {
+                    debugger.remove_watchpoint(cell);
+                }
+                let mut verified = Vec::new();
+                for breakpoint in args["breakpoints"].as_array().into_iter().flatten() {
+                    let cell =
+                        breakpoint["dataId"].as_str().and_then(|id| id.strip_prefix("cell:"));
+                    match cell.and_then(|cell| cell.parse().ok()) {
+                        Some(cell) => {
+                            debugger.add_watchpoint(cell);
+                            verified.push(json!({ "verified": true }));
+                        }
+                        None => verified.push(json!({ "verified": false })),
+                    }
+                }
+                return Ok((json!({ "breakpoints": verified }), Then::Nothing));
+            }
+            "continue" => Then::Run(Motion::Continue),
+            "next" => Then::Run(Motion::Next),
+            "stepIn" => Then::Run(Motion::StepIn),
+            "stepOut" => match debugger.loops().pop() {
+                Some(range) => Then::Run(Motion::StepOut(range.start + 1..range.end)),
+                None => Then::Run(Motion::Continue),
+            },
+            "stepBack" => Then::Run(Motion::StepBack),
+            "reverseContinue" => Then::Run(Motion::ReverseContinue),
+            "pause" => Then::Stop("pause"),
+            _ => return Err(format!("Unsupported request `{}`", command)),
+        };
+        let body = match command {
+            "continue" => json!({ "allThreadsContinued": true }),
+            _ => Value::Null,
+        };
+        Ok((body, then))
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<(), String> {
+        let path = PathBuf::from(args["program"].as_str().ok_or("Missing `program` to debug")?);
+        let code = std::fs::read_to_string(&path)
+            .map_err(|source| Error::Open { path: path.clone(), source }.to_string())?;
+        let mut debugger = Debugger::new(&code, self.args.options.overflow)
+            .map_err(|err| Error::Parse(err).report(&code))?;
+        let ignore_hash = args["ignoreHash"] == true;
+        if !ignore_hash {
+            debugger.add_marked_breakpoints(&code);
+        }
+        debugger.set_journal_limit(self.args.journal_limit);
+
+        let input = args["input"].as_str().map(PathBuf::from);
+        let input =
+            open_input(input.as_ref(), &self.args.options).map_err(|err| err.to_string())?;
+        let output = io::Output::new(Vec::new(), self.args.options.output_encoding);
+        let stop_on_entry = args["stopOnEntry"] == true;
+        self.session =
+            Some(Session { path, code, debugger, input, output, stop_on_entry, ignore_hash });
+        Ok(())
+    }
+
+    /// Replace the breakpoints set on lines (and maybe columns) of the source. Breakpoints from
+    /// `#` in the source stay.
+    fn set_breakpoints(&mut self, args: &Value) -> Value {
+        let (line_base, column_base) = (self.line_base, self.column_base);
+        let session = self.session.as_mut().expect("checked by `handle`");
+        session.debugger.clear_breakpoints();
+        if !session.ignore_hash {
+            session.debugger.add_marked_breakpoints(&session.code);
+        }
+
+        let mut breakpoints = Vec::new();
+        for breakpoint in args["breakpoints"].as_array().into_iter().flatten() {
+            let line = breakpoint["line"].as_u64().unwrap_or(0) as usize + 1 - line_base;
+            let column =
+                breakpoint["column"].as_u64().map_or(1, |column| column as usize + 1 - column_base);
+            let index = SourcePos::at(&session.code, line, column)
+                .and_then(|pos| session.debugger.add_breakpoint(pos.offset));
+            breakpoints.push(match index {
+                Some(index) => {
+                    let pos =
+                        SourcePos::find(&session.code, session.debugger.program.spans[index].start);
+                    json!({
+                        "verified": true,
+                        "line": pos.line - 1 + line_base,
+                        "column": pos.column - 1 + column_base,
+                    })
+                }
+                None => {
+                    json!({ "verified": false, "message": "No commands at or after this line" })
+                }
+            });
+        }
+        json!({ "breakpoints": breakpoints })
+    }
+
+    /// The loops around the next command as a call stack, innermost first. Each loop's frame
+    /// points at where execution is inside it, i.e. the next command or the `[` of the loop
+    /// it's in, and the program itself is the outermost frame.
+    fn stack_trace(&self) -> Value {
+        let session = self.session.as_ref().expect("checked by `handle`");
+        let (code, debugger) = (&session.code, &session.debugger);
+        let spans = &debugger.program.spans;
+        let loops = debugger.loops();
+
+        let mut offset = debugger.current_span().map_or(code.len(), |span| span.start);
+        let mut frames = Vec::new();
+        for (id, name) in loops
+            .iter()
+            .rev()
+            .map(|range| {
+                let pos = SourcePos::find(code, spans[range.start].start);
+                format!("loop at line {}, column {}", pos.line, pos.column)
+            })
+            .chain(std::iter::once(String::from("program")))
+            .enumerate()
+        {
+            let pos = SourcePos::find(code, offset);
+            frames.push(json!({
+                "id": id,
+                "name": name,
+                "source": self.source(),
+                "line": pos.line - 1 + self.line_base,
+                "column": pos.column - 1 + self.column_base,
+            }));
+            if let Some(range) = loops.get(loops.len().wrapping_sub(id + 1)) {
+                offset = spans[range.start].start;
+            }
+        }
+        json!({ "stackFrames": frames, "totalFrames": frames.len() })
+    }
+
+    fn variables(&self, args: &Value) -> Value {
+        let session = self.session.as_ref().expect("checked by `handle`");
+        let debugger = &session.debugger;
+        let machine = &debugger.machine;
+        let variable = |name: String, value: String| {
+            json!({ "name": name, "value": value, "variablesReference": 0 })
+        };
+        let variables: Vec<Value> = match args["variablesReference"].as_u64() {
+            Some(TAPE_SCOPE) => {
+                let start = args["start"].as_u64().unwrap_or(0) as usize;
+                let count =
+                    args["count"].as_u64().map_or(machine.mem.len(), |count| count as usize);
+                (start..start.saturating_add(count).min(machine.mem.len()))
+                    .map(|cell| variable(format!("[{}]", cell), describe_cell(debugger.cell(cell))))
+                    .collect()
+            }
+            Some(MACHINE_SCOPE) => vec![
+                variable(String::from("head"), machine.mem_ptr.to_string()),
+                variable(String::from("cell"), describe_cell(debugger.cell(machine.mem_ptr))),
+                variable(String::from("instruction"), machine.prg_head.to_string()),
+                variable(String::from("steps"), debugger.steps.to_string()),
+            ],
+            _ => Vec::new(),
+        };
+        json!({ "variables": variables })
+    }
+
+    /// Change a cell on the tape, or the head, the cell under it or the next instruction. Values
+    /// that don't fit, and cells past where edits may grow the tape to, are refused.
+    fn set_variable(&mut self, args: &Value) -> Result<Value, String> {
+        let session = self.session.as_mut().expect("checked by `handle`");
+        let debugger = &mut session.debugger;
+        let text = args["value"].as_str().unwrap_or_default().trim();
+        let value = parse_value(text).ok_or_else(|| format!("Invalid value `{}`", text))?;
+        let cell_value = || {
+            Some(value)
+                .filter(|&value| value <= C::MAX.to_u64())
+                .ok_or_else(|| format!("{} doesn't fit in a cell", value))
+        };
+        let index = || usize::try_from(value).map_err(|_| format!("{} is out of range", value));
+        let head = debugger.machine.mem_ptr;
+        let value = match (args["variablesReference"].as_u64(), args["name"].as_str()) {
+            (Some(TAPE_SCOPE), _) => {
+                let cell = parse_cell(&args["name"]).ok_or("Unknown cell")?;
+                debugger.set_cell(cell, cell_value()?).map_err(|err| err.to_string())?;
+                describe_cell(debugger.cell(cell))
+            }
+            (Some(MACHINE_SCOPE), Some("head")) => {
+                debugger.set_head(index()?).map_err(|err| err.to_string())?;
+                debugger.machine.mem_ptr.to_string()
+            }
+            (Some(MACHINE_SCOPE), Some("cell")) => {
+                debugger.set_cell(head, cell_value()?).map_err(|err| err.to_string())?;
+                describe_cell(debugger.cell(head))
+            }
+            (Some(MACHINE_SCOPE), Some("instruction")) => {
+                debugger.jump(index()?);
+                debugger.machine.prg_head.to_string()
+            }
+            _ => return Err(String::from("This can't be changed")),
+        };
+        Ok(json!({ "value": value }))
+    }
+
+    /// Move through the program, then tell the client where it stopped
+    fn run(&mut self, motion: Motion) -> Result<(), Error> {
+        loop {
+            let session = self.session.as_mut().expect("only run once launched");
+            let (debugger, input, output) =
+                (&mut session.debugger, &mut session.input, &mut session.output);
+            let chunked =
+                matches!(motion, Motion::Continue | Motion::StepOut(_) | Motion::ReverseContinue);
+            let result = match &motion {
+                Motion::Continue => debugger.step(CHUNK, input, output),
+                Motion::Next => debugger.step_op(1, input, output),
+                Motion::StepIn => debugger.step(1, input, output),
+                Motion::StepOut(range) => {
+                    debugger.run_while_in(range.clone(), CHUNK, input, output)
+                }
+                Motion::StepBack => Ok(debugger.step_back(1)),
+                Motion::ReverseContinue => Ok(debugger.step_back(CHUNK)),
+            };
+            self.send_output()?;
+            match result {
+                Ok(Pause::Stepped) if chunked && !self.leaving_step_out(&motion) => {
+                    // The pending request reports the stop once it's handled
+                    if self.pause_requested() {
+                        return Ok(());
+                    }
+                }
+                Ok(Pause::Stepped | Pause::Start) => return self.stopped("step", None),
+                Ok(Pause::Breakpoint(_)) => return self.stopped("breakpoint", None),
+                Ok(Pause::Watchpoint { cell, old, new }) => {
+                    let text = format!("Cell {} changed from {} to {}", cell, old, new);
+                    return self.stopped("data breakpoint", Some(text));
+                }
+                Ok(Pause::Finished) => {
+                    let body = json!({ "exitCode": 0 });
+                    self.send(json!({ "type": "event", "event": "exited", "body": body }))?;
+                    return self.send(json!({ "type": "event", "event": "terminated" }));
+                }
+                Err(err) => {
+                    let session = self.session.as_ref().expect("only run once launched");
+                    let report = err.report(&session.code);
+                    self.send(json!({
+                        "type": "event",
+                        "event": "output",
+                        "body": { "category": "stderr", "output": report },
+                    }))?;
+                    return self.stopped("exception", Some(err.to_string()));
+                }
+            }
+        }
+    }
+
+    /// Whether stepping out is done, having left the loop rather than run out of commands
+    fn leaving_step_out(&self, motion: &Motion) -> bool {
+        let session = self.session.as_ref().expect("only run once launched");
+        match motion {
+            Motion::StepOut(range) => !range.contains(&session.debugger.machine.prg_head),
+            _ => false,
+        }
+    }
+
+    /// Check the requests that came in while the program ran for a `pause`, keeping the rest
+    /// for later. A client that went away counts as pausing too.
+    fn pause_requested(&mut self) -> bool {
+        loop {
+            match self.incoming.try_recv() {
+                Ok(request) => self.pending.push_back(request),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return true,
+            }
+        }
+        self.pending.iter().any(|request| {
+            matches!(request["command"].as_str(), Some("pause" | "disconnect" | "terminate"))
+        })
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<String>) -> Result<(), Error> {
+        let mut body = json!({ "reason": reason, "threadId": 1, "allThreadsStopped": true });
+        if let Some(text) = text {
+            body["text"] = Value::String(text);
+        }
+        self.send(json!({ "type": "event", "event": "stopped", "body": body }))
+    }
+
+    /// Send what the program wrote since the last stop as an output event
+    fn send_output(&mut self) -> Result<(), Error> {
+        let session = self.session.as_mut().expect("only run once launched");
+        let bytes = std::mem::take(session.output.get_mut());
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        let output = String::from_utf8_lossy(&bytes).into_owned();
+        self.send(json!({
+            "type": "event",
+            "event": "output",
+            "body": { "category": "stdout", "output": output },
+        }))
+    }
+
+    fn source(&self) -> Value {
+        let session = self.session.as_ref().expect("checked by `handle`");
+        let name = session.path.file_name().map(|name| name.to_string_lossy().into_owned());
+        json!({ "name": name, "path": session.path.to_string_lossy() })
+    }
+
+    fn respond(&mut self, request: &Value, body: Result<Value, String>) -> Result<(), Error> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": body.is_ok(),
+        });
+        match body {
+            Ok(Value::Null) => {}
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = Value::String(message),
+        }
+        self.send(response)
+    }
+
+    fn send(&mut self, mut message: Value) -> Result<(), Error> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        write!(self.writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)
+            .and_then(|()| self.writer.flush())
+            .map_err(Error::Connection)
+    }
+}
+
+/// Index of a tape variable named like `[12]`
+fn parse_cell(name: &Value) -> Option<usize> {
+    name.as_str()?.strip_prefix('[')?.strip_suffix(']')?.parse().ok()
+}
+
+/// Parse a decimal or `0x` hexadecimal number, or a character in single quotes
+fn parse_value(text: &str) -> Option<u64> {
+    if let Some(hex) = text.strip_prefix("0x") {
+        return u64::from_str_radix(hex, 16).ok();
+    }
+    if let Some(ch) = text.strip_prefix('\'').and_then(|text| text.strip_suffix('\'')) {
+        let mut chars = ch.chars();
+        return match (chars.next(), chars.next()) {
+            (Some(ch), None) => Some(u64::from(ch)),
+            _ => None,
+        };
+    }
+    text.parse().ok()
+}
+
+fn describe_cell(value: u64) -> String {
+    match u8::try_from(value) {
+        Ok(byte) if byte.is_ascii_graphic() || byte == b' ' => {
+            format!("{} '{}'", value, byte as char)
+        }
+        _ => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use brainfetch_core::command_opt::OverflowMode;
+    use std::io::Cursor;
+
+    /// Send `requests` to a server as one scripted session, and return everything it sent back
+    fn session(requests: &[Value]) -> Vec<Value> {
+        let mut script = Vec::new();
+        for (seq, request) in requests.iter().enumerate() {
+            let mut request = request.clone();
+            request["seq"] = json!(seq + 1);
+            request["type"] = json!("request");
+            let body = request.to_string();
+            write!(script, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        }
+        let args = DapArgs {
+            journal_limit: DEFAULT_JOURNAL_LIMIT,
+            options: crate::Options {
+                output_encoding: io::OutputEncoding::Raw,
+                eof: io::EofBehavior::Zero,
+                strict_input: false,
+                cell_bits: 8,
+                overflow: OverflowMode::Wrap,
+            },
+        };
+        let mut sent = Vec::new();
+        Server::<u8>::new(&args, spawn_reader(Cursor::new(script)), Box::new(&mut sent))
+            .serve()
+            .unwrap();
+
+        let mut reader = Cursor::new(sent);
+        std::iter::from_fn(|| read_message(&mut reader).unwrap()).collect()
+    }
+
+    /// Pick the response to `command` out of `messages`
+    fn response<'a>(messages: &'a [Value], command: &str) -> &'a Value {
+        messages
+            .iter()
+            .find(|message| message["type"] == "response" && message["command"] == command)
+            .unwrap_or_else(|| panic!("no response to `{}`", command))
+    }
+
+    /// Events in the order they were sent, as `event` or `event:reason`
+    fn events(messages: &[Value]) -> Vec<String> {
+        messages
+            .iter()
+            .filter(|message| message["type"] == "event")
+            .map(|message| match message["body"]["reason"].as_str() {
+                Some(reason) => format!("{}:{}", message["event"].as_str().unwrap(), reason),
+                None => message["event"].as_str().unwrap().to_owned(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn scripted_session() {
+        let path = std::env::temp_dir().join(format!("brainfetch-dap-{}.bf", std::process::id()));
+        std::fs::write(&path, "++\n>+\n<.\n").unwrap();
+        let source = json!({ "path": path });
+        let messages = session(&[
+            json!({ "command": "initialize", "arguments": { "linesStartAt1": true } }),
+            json!({ "command": "launch", "arguments": { "program": path, "stopOnEntry": true } }),
+            json!({ "command": "setBreakpoints", "arguments": {
+                "source": source,
+                "breakpoints": [{ "line": 2, "column": 2 }, { "line": 9 }],
+            }}),
+            json!({ "command": "configurationDone" }),
+            json!({ "command": "continue" }),
+            json!({ "command": "setVariable", "arguments": {
+                "variablesReference": TAPE_SCOPE, "name": "[0]", "value": "'A'",
+            }}),
+            json!({ "command": "continue" }),
+            json!({ "command": "reverseContinue" }),
+            json!({ "command": "variables", "arguments": { "variablesReference": MACHINE_SCOPE } }),
+            json!({ "command": "frobnicate" }),
+        ]);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(response(&messages, "initialize")["body"]["supportsStepBack"], true);
+        assert_eq!(
+            response(&messages, "setBreakpoints")["body"]["breakpoints"],
+            json!([
+                { "verified": true, "line": 2, "column": 2 },
+                { "verified": false, "message": "No commands at or after this line" },
+            ]),
+        );
+        assert_eq!(response(&messages, "setVariable")["body"]["value"], "65 'A'");
+        assert_eq!(
+            events(&messages),
+            [
+                "initialized",
+                "stopped:entry",
+                "stopped:breakpoint",
+                // The cell set at the breakpoint is printed
+                "output",
+                "exited",
+                "terminated",
+                // Running backwards from the end stops at the breakpoint again
+                "stopped:breakpoint",
+            ],
+        );
+        let output = messages.iter().find(|message| message["event"] == "output").unwrap();
+        assert_eq!(output["body"]["output"], "A");
+        let variables = &response(&messages, "variables")["body"]["variables"];
+        assert_eq!(variables[2]["name"], "instruction");
+        assert_eq!(variables[2]["value"], "3");
+
+        let unknown = response(&messages, "frobnicate");
+        assert_eq!(unknown["success"], false);
+        assert_eq!(unknown["message"], "Unsupported request `frobnicate`");
+        // Every message is numbered in order
+        for (seq, message) in (1..).zip(&messages) {
+            assert_eq!(message["seq"], seq);
+        }
+    }
+
+    #[test]
+    fn set_variable_refuses_out_of_range() {
+        let name = format!("brainfetch-dap-set-{}.bf", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, "[-]").unwrap();
+        let set = |reference, name, value| {
+            json!({ "command": "setVariable", "arguments": {
+                "variablesReference": reference, "name": name, "value": value,
+            }})
+        };
+        let messages = session(&[
+            json!({ "command": "initialize", "arguments": {} }),
+            json!({ "command": "launch", "arguments": { "program": path, "stopOnEntry": true } }),
+            json!({ "command": "configurationDone" }),
+            set(MACHINE_SCOPE, "head", "0x10000000000000000"),
+            set(MACHINE_SCOPE, "head", "0x10000000000"),
+            set(TAPE_SCOPE, "[1099511627776]", "1"),
+            set(MACHINE_SCOPE, "cell", "256"),
+            set(MACHINE_SCOPE, "head", "100"),
+            json!({ "command": "next" }),
+        ]);
+        std::fs::remove_file(&path).unwrap();
+
+        let replies: Vec<(bool, String)> = messages
+            .iter()
+            .filter(|message| message["command"] == "setVariable")
+            .map(|message| {
+                let text = message["message"].as_str().or(message["body"]["value"].as_str());
+                (message["success"] == true, text.unwrap().to_owned())
+            })
+            .collect();
+        assert_eq!(
+            replies,
+            [
+                (false, String::from("Invalid value `0x10000000000000000`")),
+                (false, String::from("Cells past 16777215 can't be edited")),
+                (false, String::from("Cells past 16777215 can't be edited")),
+                (false, String::from("256 doesn't fit in a cell")),
+                (true, String::from("100")),
+            ],
+        );
+        // Stepping from the moved head doesn't read past the tape
+        assert_eq!(response(&messages, "next")["success"], true);
+    }
+}
@@ -0,0 +1,310 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use clap::Args;
+use brainfetch_core::cell::Cell;
+use brainfetch_core::command::{excerpt, SourcePos};
+use brainfetch_core::debug::{Debugger, Pause, DEFAULT_JOURNAL_LIMIT};
+use brainfetch_core::{io, Error};
+
+#[derive(Args)]
+pub struct DebugArgs {
+    /// BrainF*** file to debug
+    pub file: PathBuf,
+
+    /// File the program reads its input from, since the debugger reads commands from stdin.
+    /// Without it, the program's input is empty.
+    #[arg(long)]
+    pub input: Option<PathBuf>,
+
+    /// Don't treat `#` in the source as a breakpoint, for programs that use it in comments
+    #[arg(long)]
+    pub ignore_hash: bool,
+
+    /// Number of steps to remember for stepping backwards. Each one takes up 64 bytes.
+    #[arg(long, value_name = "STEPS", default_value_t = DEFAULT_JOURNAL_LIMIT)]
+    pub journal_limit: usize,
+
+    #[command(flatten)]
+    pub options: crate::Options,
+}
+
+const HELP: &str = "\
+Commands (an empty line repeats the last one):
+  s, step [N]            Execute the next N commands (default 1)
+  si, stepi [N]          Execute the next N optimized instructions, stepping over loops like [-]
+  c, continue            Run until a breakpoint, a watchpoint or the end of the program
+  rs, rstep [N]          Undo the last N commands (default 1)
+  rc, rcontinue          Run backwards until a breakpoint, a watchpoint or the earliest step kept
+  lw, lastwrite CELL     Run backwards to the command that last wrote to a cell
+  g, goto STEP           Go backwards or forwards to the point after STEP commands
+  b, break LINE[:COL]    Pause before the first command at or after a position
+  d, delete LINE[:COL]   Remove the breakpoint on the first command at or after a position
+  w, watch CELL          Pause whenever a cell changes
+  unwatch CELL           Stop watching a cell
+  t, tape [RADIUS]       Show the cells around the head (default 8 on each side)
+  l, where               Show where the program is paused
+  i, info                List breakpoints and watchpoints
+  r, restart             Start the program over, keeping breakpoints and watchpoints
+  q, quit                Leave the debugger
+Going backwards doesn't take back output, and going forwards again replays the steps instead of
+reading input or writing output a second time.
+A '#' in the source sets a breakpoint on the command after it, unless --ignore-hash is given.
+";
+
+/// Debug the program in `code` interactively, reading debugger commands from stdin
+pub fn run(args: &DebugArgs, code: &str) -> Result<(), Error> {
+    match args.options.cell_bits {
+        8 => Session::<u8>::new(args, code)?.repl(),
+        16 => Session::<u16>::new(args, code)?.repl(),
+        32 => Session::<u32>::new(args, code)?.repl(),
+        _ => Session::<u64>::new(args, code)?.repl(),
+    }
+}
+
+/// Set up a debugger for `code` as `args` ask for
+pub fn new_debugger<C: Cell>(args: &DebugArgs, code: &str) -> Result<Debugger<C>, Error> {
+    let mut debugger = Debugger::new(code, args.options.overflow).map_err(Error::Parse)?;
+    if !args.ignore_hash {
+        debugger.add_marked_breakpoints(code);
+    }
+    debugger.set_journal_limit(args.journal_limit);
+    Ok(debugger)
+}
+
+/// Open the program's input from the start: the file at `path`, or nothing at all
+pub fn open_input(
+    path: Option<&PathBuf>,
+    options: &crate::Options,
+) -> Result<io::Input<Box<dyn Read>>, Error> {
+    let (eof, strict) = (options.eof, options.strict_input);
+    Ok(match path {
+        Some(path) => {
+            let file =
+                File::open(path).map_err(|source| Error::Open { path: path.clone(), source })?;
+            io::Input::new(BufReader::new(file), eof, strict).boxed()
+        }
+        None => io::Input::new(std::io::empty(), eof, strict).boxed(),
+    })
+}
+
+struct Session<'a, C: Cell> {
+    args: &'a DebugArgs,
+    code: &'a str,
+    debugger: Debugger<C>,
+    input: io::Input<Box<dyn Read>>,
+    output: io::Output<Box<dyn Write>>,
+}
+
+impl<'a, C: Cell> Session<'a, C> {
+    fn new(args: &'a DebugArgs, code: &'a str) -> Result<Self, Error> {
+        let debugger = new_debugger(args, code)?;
+        let output = io::Output::new(std::io::stdout(), args.options.output_encoding).boxed();
+        let input = open_input(args.input.as_ref(), &args.options)?;
+        Ok(Self { args, code, debugger, input, output })
+    }
+
+    fn repl(&mut self) -> Result<(), Error> {
+        println!(
+            "Debugging {} ({} commands). Type `help` for a list of commands.",
+            self.args.file.display(),
+            self.debugger.program.commands.len()
+        );
+        self.show_position();
+
+        let mut last = String::new();
+        let mut lines = std::io::stdin().lock().lines();
+        loop {
+            print!("(bfdb) ");
+            std::io::stdout().flush().map_err(Error::Output)?;
+            let line = match lines.next() {
+                Some(line) => line.map_err(Error::Input)?,
+                None => break,
+            };
+            if !line.trim().is_empty() {
+                last = line;
+            }
+            let mut words = last.split_whitespace();
+            let command = words.next().unwrap_or("");
+            let arg = words.next();
+            match self.command(command, arg) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(message) => println!("{}", message),
+            }
+        }
+        Ok(())
+    }
+
+    /// Carry out one debugger command. Returns `Ok(false)` to quit, or a message explaining
+    /// what was wrong with the command.
+    fn command(&mut self, command: &str, arg: Option<&str>) -> Result<bool, String> {
+        let (input, output) = (&mut self.input, &mut self.output);
+        let result = match command {
+            "s" | "step" => self.debugger.step(parse_count(arg)?, input, output),
+            "si" | "stepi" => self.debugger.step_op(parse_count(arg)?, input, output),
+            "c" | "continue" => self.debugger.cont(input, output),
+            "rs" | "rstep" => Ok(self.debugger.step_back(parse_count(arg)?)),
+            "rc" | "rcontinue" => Ok(self.debugger.reverse_cont()),
+            "lw" | "lastwrite" => Ok(self.debugger.back_to_write(parse_cell(arg)?)),
+            "g" | "goto" => {
+                let step = arg.ok_or("Expected a step count")?;
+                let step = step.parse().map_err(|_| format!("Invalid step count `{}`", step))?;
+                self.debugger.goto(step, input, output)
+            }
+            "b" | "break" => {
+                let offset = self.parse_position(arg)?;
+                match self.debugger.add_breakpoint(offset) {
+                    Some(index) => println!("Breakpoint at {}", self.describe(index)),
+                    None => println!("No commands after that position"),
+                }
+                return Ok(true);
+            }
+            "d" | "delete" => {
+                let offset = self.parse_position(arg)?;
+                match self.debugger.remove_breakpoint(offset) {
+                    true => println!("Deleted breakpoint"),
+                    false => println!("No breakpoint there"),
+                }
+                return Ok(true);
+            }
+            "w" | "watch" => {
+                let cell = parse_cell(arg)?;
+                self.debugger.add_watchpoint(cell);
+                println!("Watching cell {} (value {})", cell, self.debugger.cell(cell));
+                return Ok(true);
+            }
+            "unwatch" => {
+                match self.debugger.remove_watchpoint(parse_cell(arg)?) {
+                    true => println!("Stopped watching"),
+                    false => println!("That cell isn't watched"),
+                }
+                return Ok(true);
+            }
+            "t" | "tape" => {
+                let radius = arg.map_or(Ok(8), |arg| arg.parse().map_err(|_| "Invalid radius"))?;
+                self.show_tape(radius);
+                return Ok(true);
+            }
+            "l" | "where" => {
+                self.show_position();
+                return Ok(true);
+            }
+            "i" | "info" => {
+                self.show_info();
+                return Ok(true);
+            }
+            "r" | "restart" => {
+                self.debugger.restart();
+                self.input = open_input(self.args.input.as_ref(), &self.args.options)
+                    .map_err(|err| err.to_string())?;
+                self.show_position();
+                return Ok(true);
+            }
+            "h" | "help" => {
+                print!("{}", HELP);
+                return Ok(true);
+            }
+            "q" | "quit" => return Ok(false),
+            _ => return Err(format!("Unknown command `{}`. Type `help` for a list.", command)),
+        };
+
+        match result {
+            Ok(Pause::Stepped) => {}
+            Ok(Pause::Breakpoint(_)) => println!("Breakpoint hit"),
+            Ok(Pause::Watchpoint { cell, old, new }) => {
+                println!("Cell {} changed from {} to {}", cell, old, new)
+            }
+            Ok(Pause::Finished) => {}
+            Ok(Pause::Start) => println!(
+                "Can't go back any further, the journal starts at step {}",
+                self.debugger.earliest_step()
+            ),
+            Err(err) => print!("{}", err.report(self.code)),
+        }
+        self.show_position();
+        Ok(true)
+    }
+
+    /// Byte offset in the source of a `LINE[:COLUMN]` argument
+    fn parse_position(&self, arg: Option<&str>) -> Result<usize, String> {
+        let arg = arg.ok_or("Expected a position like 12 or 12:5")?;
+        let (line, column) = arg.split_once(':').unwrap_or((arg, "1"));
+        let (line, column) = match (line.parse(), column.parse()) {
+            (Ok(line), Ok(column)) => (line, column),
+            _ => return Err(format!("Invalid position `{}`", arg)),
+        };
+        SourcePos::at(self.code, line, column)
+            .map(|pos| pos.offset)
+            .ok_or_else(|| format!("There is no line {}", line))
+    }
+
+    /// Line and column of the command at instruction `index`
+    fn describe(&self, index: usize) -> String {
+        let pos = SourcePos::find(self.code, self.debugger.program.spans[index].start);
+        format!("line {}, column {}", pos.line, pos.column)
+    }
+
+    fn show_position(&self) {
+        let machine = &self.debugger.machine;
+        let span = match self.debugger.current_span() {
+            Some(span) => span,
+            None => {
+                println!("Program finished after {} steps", self.debugger.steps);
+                return;
+            }
+        };
+        let head = machine.mem_ptr;
+        println!(
+            "Step {}, at {}, head at cell {} (value {}):",
+            self.debugger.steps,
+            self.describe(machine.prg_head),
+            head,
+            self.debugger.cell(head)
+        );
+        print!("{}", excerpt(self.code, span));
+        if let Some(op) = self.debugger.current_op() {
+            println!("Next instruction: {:?}", self.debugger.optimized.commands[op]);
+        }
+    }
+
+    fn show_tape(&self, radius: usize) {
+        let head = self.debugger.machine.mem_ptr;
+        for cell in head.saturating_sub(radius)..=head.saturating_add(radius) {
+            let value = self.debugger.cell(cell);
+            let marker = if cell == head { "->" } else { "  " };
+            match u8::try_from(value) {
+                Ok(byte) if byte.is_ascii_graphic() || byte == b' ' => {
+                    println!("{} {:>6}: {:>5} '{}'", marker, cell, value, byte as char)
+                }
+                _ => println!("{} {:>6}: {:>5}", marker, cell, value),
+            }
+        }
+    }
+
+    fn show_info(&self) {
+        let breakpoints: Vec<usize> = self.debugger.breakpoints().collect();
+        if breakpoints.is_empty() {
+            println!("No breakpoints");
+        }
+        for index in breakpoints {
+            println!("Breakpoint at {}", self.describe(index));
+        }
+        let watchpoints: Vec<usize> = self.debugger.watchpoints().collect();
+        if watchpoints.is_empty() {
+            println!("No watchpoints");
+        }
+        for cell in watchpoints {
+            println!("Watching cell {} (value {})", cell, self.debugger.cell(cell));
+        }
+    }
+}
+
+fn parse_count(arg: Option<&str>) -> Result<u64, String> {
+    arg.map_or(Ok(1), |arg| arg.parse().map_err(|_| format!("Invalid count `{}`", arg)))
+}
+
+fn parse_cell(arg: Option<&str>) -> Result<usize, String> {
+    let arg = arg.ok_or("Expected a cell index")?;
+    arg.parse().map_err(|_| format!("Invalid cell `{}`", arg))
+}
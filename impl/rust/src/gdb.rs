@@ -0,0 +1,608 @@
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use clap::Args;
+use brainfetch_core::cell::Cell;
+use brainfetch_core::debug::{Debugger, Pause, EDIT_LIMIT};
+use brainfetch_core::{io, Error, Fault};
+use crate::debugger::{new_debugger, open_input, DebugArgs};
+
+#[derive(Args)]
+pub struct GdbArgs {
+    #[command(flatten)]
+    pub debug: DebugArgs,
+
+    /// Address to wait for gdb to connect on
+    #[arg(long, value_name = "ADDRESS", default_value = "127.0.0.1:1234")]
+    listen: String,
+
+    /// Talk to gdb over stdin and stdout instead of a socket, for
+    /// `target remote | brainfetch gdb --stdio FILE`
+    #[arg(long)]
+    stdio: bool,
+}
+
+/// Registers, in the order of the `g` packet. The program counter is the source offset of the
+/// next command, so breakpoints set on an address (`break *OFFSET`) land on that command.
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.brainfetch.core">
+    <reg name="pc" bitsize="64" type="code_ptr" regnum="0"/>
+    <reg name="index" bitsize="64" type="uint64"/>
+    <reg name="head" bitsize="64" type="data_ptr"/>
+    <reg name="steps" bitsize="64" type="uint64"/>
+  </feature>
+</target>
+"#;
+const REGISTER_NAMES: [&str; 4] = ["pc", "index", "head", "steps"];
+
+/// Largest packet gdb may send us, and the most memory it may read at once
+const PACKET_SIZE: usize = 0x1000;
+
+/// Most bytes a single watchpoint may cover, since every cell in it is watched on its own
+const WATCH_SIZE: u64 = 0x100;
+
+/// Commands run between checks for an interrupt from gdb while continuing
+const CHUNK: u64 = 1 << 16;
+
+// Signals reported in stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGFPE: u8 = 8;
+const SIGSEGV: u8 = 11;
+const SIGPIPE: u8 = 13;
+
+/// Serve the program in `code` to a gdb (or lldb) client over the GDB remote serial protocol.
+/// The tape is memory starting at address 0, one cell after the other in little-endian.
+pub fn run(args: &GdbArgs, code: &str) -> Result<(), Error> {
+    let connection = if args.stdio {
+        Connection::new(Box::new(std::io::stdin()), Box::new(std::io::stdout()))
+    } else {
+        let listener = TcpListener::bind(&args.listen).map_err(Error::Connection)?;
+        let address = listener.local_addr().map_err(Error::Connection)?;
+        eprintln!("Waiting for gdb to connect on {}", address);
+        let (stream, peer) = listener.accept().map_err(Error::Connection)?;
+        eprintln!("Connected to {}", peer);
+        let reader = stream.try_clone().map_err(Error::Connection)?;
+        Connection::new(Box::new(reader), Box::new(stream))
+    };
+    match args.debug.options.cell_bits {
+        8 => Stub::<u8>::new(args, code, connection)?.serve(),
+        16 => Stub::<u16>::new(args, code, connection)?.serve(),
+        32 => Stub::<u32>::new(args, code, connection)?.serve(),
+        _ => Stub::<u64>::new(args, code, connection)?.serve(),
+    }
+}
+
+/// What gdb sent
+enum Packet {
+    Command(String),
+    /// Ctrl-C, sent on its own outside of a packet
+    Interrupt,
+}
+
+/// Packet layer of the protocol. Incoming bytes are read on a separate thread, so a running
+/// program can check for an interrupt without blocking.
+struct Connection {
+    incoming: Receiver<Vec<u8>>,
+    buffer: VecDeque<u8>,
+    writer: Box<dyn Write>,
+    /// Whether packets are still acknowledged with `+`, until gdb turns that off
+    ack: bool,
+}
+
+impl Connection {
+    fn new(mut reader: Box<dyn Read + Send>, writer: Box<dyn Write>) -> Self {
+        let (sender, incoming) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buffer = [0u8; 4096];
+            loop {
+                match reader.read(&mut buffer) {
+                    Ok(0) => break,
+                    Ok(len) => {
+                        if sender.send(buffer[..len].to_vec()).is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                    Err(_) => break,
+                }
+            }
+        });
+        Self { incoming, buffer: VecDeque::new(), writer, ack: true }
+    }
+
+    /// Next byte from gdb, or `None` once it disconnected
+    fn byte(&mut self) -> Option<u8> {
+        while self.buffer.is_empty() {
+            self.buffer.extend(self.incoming.recv().ok()?);
+        }
+        self.buffer.pop_front()
+    }
+
+    /// Wait for the next packet, or `None` once gdb disconnected
+    fn receive(&mut self) -> Result<Option<Packet>, Error> {
+        loop {
+            match self.byte() {
+                None => return Ok(None),
+                Some(0x03) => return Ok(Some(Packet::Interrupt)),
+                Some(b'$') => {}
+                // Acknowledgements and noise between packets
+                Some(_) => continue,
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.byte() {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                }
+            }
+            let checksum = match (self.byte(), self.byte()) {
+                (Some(high), Some(low)) => parse_hex(&[high, low]),
+                _ => return Ok(None),
+            };
+            if checksum != Some(u64::from(checksum_of(&data))) && self.ack {
+                self.write(b"-")?;
+                continue;
+            }
+            if self.ack {
+                self.write(b"+")?;
+            }
+            return Ok(Some(Packet::Command(String::from_utf8_lossy(&data).into_owned())));
+        }
+    }
+
+    /// Send a packet, waiting for gdb to acknowledge it unless that's turned off
+    fn send(&mut self, data: &str) -> Result<(), Error> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        loop {
+            self.write(packet.as_bytes())?;
+            if !self.ack {
+                return Ok(());
+            }
+            match self.ack() {
+                Some(b'+') | None => return Ok(()),
+                Some(_) => {}
+            }
+        }
+    }
+
+    /// Wait for gdb to acknowledge a packet with `+` or ask for it again with `-`, or `None`
+    /// once it disconnected. Packets and interrupts it sent in the meantime stay buffered for
+    /// `receive`.
+    fn ack(&mut self) -> Option<u8> {
+        let mut pos = 0;
+        let mut in_packet = false;
+        let mut checksum_left = 0;
+        loop {
+            while pos >= self.buffer.len() {
+                self.buffer.extend(self.incoming.recv().ok()?);
+            }
+            let byte = self.buffer[pos];
+            if checksum_left > 0 {
+                checksum_left -= 1;
+            } else if in_packet {
+                if byte == b'#' {
+                    in_packet = false;
+                    checksum_left = 2;
+                }
+            } else {
+                match byte {
+                    b'+' | b'-' => return self.buffer.remove(pos),
+                    b'$' => in_packet = true,
+                    _ => {}
+                }
+            }
+            pos += 1;
+        }
+    }
+
+    /// Whether gdb sent Ctrl-C since the last packet. Anything else it sent in the meantime
+    /// is kept for `receive`.
+    fn interrupted(&mut self) -> bool {
+        loop {
+            match self.incoming.try_recv() {
+                Ok(bytes) => self.buffer.extend(bytes),
+                Err(TryRecvError::Empty) => break,
+                // Stop once gdb went away, `receive` notices later
+                Err(TryRecvError::Disconnected) => return true,
+            }
+        }
+        match self.buffer.iter().position(|&byte| byte == 0x03) {
+            Some(pos) => {
+                self.buffer.remove(pos);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.writer.write_all(bytes).map_err(Error::Connection)?;
+        self.writer.flush().map_err(Error::Connection)
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn parse_hex(digits: &[u8]) -> Option<u64> {
+    u64::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Parse an `ADDR,LEN` pair of hex numbers
+fn parse_range(arg: &str) -> Option<(u64, u64)> {
+    let (addr, len) = arg.split_once(',')?;
+    Some((parse_hex(addr.as_bytes())?, parse_hex(len.as_bytes())?))
+}
+
+struct Stub<'a, C: Cell> {
+    args: &'a GdbArgs,
+    code: &'a str,
+    debugger: Debugger<C>,
+    input: io::Input<Box<dyn Read>>,
+    /// The program's output, sent to gdb as console output whenever the program stops
+    output: io::Output<Vec<u8>>,
+    connection: Connection,
+    cell_bytes: u64,
+}
+
+impl<'a, C: Cell> Stub<'a, C> {
+    fn new(args: &'a GdbArgs, code: &'a str, connection: Connection) -> Result<Self, Error> {
+        Ok(Self {
+            args,
+            code,
+            debugger: new_debugger(&args.debug, code)?,
+            input: open_input(args.debug.input.as_ref(), &args.debug.options)?,
+            output: io::Output::new(Vec::new(), args.debug.options.output_encoding),
+            connection,
+            cell_bytes: u64::from(args.debug.options.cell_bits / 8),
+        })
+    }
+
+    fn serve(&mut self) -> Result<(), Error> {
+        while let Some(packet) = self.connection.receive()? {
+            let command = match packet {
+                Packet::Command(command) => command,
+                // The program isn't running, so just say where it's stopped
+                Packet::Interrupt => String::from("?"),
+            };
+            match self.handle(&command)? {
+                Some(reply) => self.connection.send(&reply)?,
+                None => return Ok(()),
+            }
+            // The `OK` is still acknowledged, but nothing after it
+            if command == "QStartNoAckMode" {
+                self.connection.ack = false;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reply to one packet, or `None` to end the session
+    fn handle(&mut self, command: &str) -> Result<Option<String>, Error> {
+        let (input, output) = (&mut self.input, &mut self.output);
+        let reply = match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "QStartNoAckMode" => String::from("OK"),
+            "qAttached" => String::from("1"),
+            "qC" => String::from("QC1"),
+            "qfThreadInfo" => String::from("m1"),
+            "qsThreadInfo" => String::from("l"),
+            "vCont?" => String::from("vCont;c;C;s;S"),
+            "g" => (0..REGISTER_NAMES.len())
+                .map(|reg| hex(&self.register(reg).to_le_bytes()))
+                .collect(),
+            "c" => self.resume(false)?,
+            "s" => {
+                let result = self.debugger.step(1, input, output);
+                self.stop_reply(result)?
+            }
+            "bc" => self.resume(true)?,
+            "bs" => {
+                let pause = self.debugger.step_back(1);
+                self.stop_reply(Ok(pause))?
+            }
+            "k" => return Ok(None),
+            "D" => {
+                self.connection.send("OK")?;
+                return Ok(None);
+            }
+            _ if command.starts_with("qSupported") => format!(
+                "PacketSize={:x};QStartNoAckMode+;swbreak+;qXfer:features:read+;\
+                 ReverseStep+;ReverseContinue+;vContSupported+",
+                PACKET_SIZE
+            ),
+            _ if command.starts_with("qXfer:features:read:target.xml:") => {
+                let arg = &command["qXfer:features:read:target.xml:".len()..];
+                match parse_range(arg) {
+                    Some((offset, len)) => {
+                        let start = (offset as usize).min(TARGET_XML.len());
+                        let end = start.saturating_add(len as usize).min(TARGET_XML.len());
+                        let more = if end < TARGET_XML.len() { 'm' } else { 'l' };
+                        format!("{}{}", more, &TARGET_XML[start..end])
+                    }
+                    None => String::from("E01"),
+                }
+            }
+            _ if command.starts_with("qRegisterInfo") => {
+                match usize::from_str_radix(&command["qRegisterInfo".len()..], 16) {
+                    Ok(reg) if reg < REGISTER_NAMES.len() => format!(
+                        "name:{};bitsize:64;offset:{};encoding:uint;format:hex;\
+                         set:General Purpose Registers;{}",
+                        REGISTER_NAMES[reg],
+                        reg * 8,
+                        if reg == 0 { "generic:pc;" } else { "" }
+                    ),
+                    _ => String::from("E45"),
+                }
+            }
+            _ if command.starts_with("qRcmd,") => self.monitor(&command["qRcmd,".len()..])?,
+            _ if command.starts_with("H") => String::from("OK"),
+            _ if command.starts_with("vCont;") => match command.as_bytes().get(6) {
+                Some(b'c' | b'C') => self.resume(false)?,
+                Some(b's' | b'S') => {
+                    let result = self.debugger.step(1, input, output);
+                    self.stop_reply(result)?
+                }
+                _ => String::new(),
+            },
+            _ if command.starts_with("vKill") => {
+                self.connection.send("OK")?;
+                return Ok(None);
+            }
+            _ if command.starts_with('p') => match parse_hex(&command.as_bytes()[1..]) {
+                Some(reg) if (reg as usize) < REGISTER_NAMES.len() => {
+                    hex(&self.register(reg as usize).to_le_bytes())
+                }
+                _ => String::from("E01"),
+            },
+            _ if command.starts_with('P') => self.write_register(&command[1..]),
+            _ if command.starts_with('m') => match parse_range(&command[1..]) {
+                Some((addr, len)) => self.read_memory(addr, len.min(PACKET_SIZE as u64 / 2)),
+                None => String::from("E01"),
+            },
+            _ if command.starts_with('M') => self.write_memory(&command[1..]),
+            _ if command.starts_with('Z') || command.starts_with('z') => {
+                self.set_breakpoint(command.starts_with('Z'), &command[1..])
+            }
+            // Anything else isn't supported, which gdb expects to be answered with nothing
+            _ => String::new(),
+        };
+        Ok(Some(reply))
+    }
+
+    /// Value of register `reg`, numbered like in `TARGET_XML`
+    fn register(&self, reg: usize) -> u64 {
+        let machine = &self.debugger.machine;
+        match reg {
+            0 => self.debugger.current_span().map_or(self.code.len(), |span| span.start) as u64,
+            1 => machine.prg_head as u64,
+            2 => machine.mem_ptr as u64,
+            _ => self.debugger.steps,
+        }
+    }
+
+    /// Handle `REG=VALUE`. Setting the program counter or instruction index jumps there, and
+    /// setting the head moves it, but the step count can't be changed.
+    fn write_register(&mut self, arg: &str) -> String {
+        let (reg, value) = match arg.split_once('=') {
+            Some((reg, value)) => (parse_hex(reg.as_bytes()), value.as_bytes()),
+            None => return String::from("E01"),
+        };
+        // Values come in target byte order
+        let mut bytes = [0u8; 8];
+        for (byte, digits) in bytes.iter_mut().zip(value.chunks(2)) {
+            match parse_hex(digits) {
+                Some(value) => *byte = value as u8,
+                None => return String::from("E01"),
+            }
+        }
+        let value = u64::from_le_bytes(bytes) as usize;
+        match reg {
+            Some(0) => match self.debugger.command_at(value) {
+                Some(index) => self.debugger.jump(index),
+                None => self.debugger.jump(usize::MAX),
+            },
+            Some(1) => self.debugger.jump(value),
+            Some(2) => {
+                if self.debugger.set_head(value).is_err() {
+                    return String::from("E01");
+                }
+            }
+            _ => return String::from("E01"),
+        }
+        String::from("OK")
+    }
+
+    fn read_memory(&self, addr: u64, len: u64) -> String {
+        let bytes: Vec<u8> = (addr..addr.saturating_add(len))
+            .map(|addr| {
+                let value = self.debugger.cell((addr / self.cell_bytes) as usize);
+                (value >> (8 * (addr % self.cell_bytes))) as u8
+            })
+            .collect();
+        hex(&bytes)
+    }
+
+    /// Handle `ADDR,LEN:BYTES`, changing the bytes of the cells they cover
+    fn write_memory(&mut self, arg: &str) -> String {
+        let (addr, data) = match arg.split_once(':') {
+            Some((range, data)) => match parse_range(range) {
+                Some((addr, _)) => (addr, data.as_bytes()),
+                None => return String::from("E01"),
+            },
+            None => return String::from("E01"),
+        };
+        let bytes: Option<Vec<u64>> = data.chunks(2).map(parse_hex).collect();
+        let Some(bytes) = bytes else {
+            return String::from("E01");
+        };
+        // Refuse the whole write up front rather than leaving it half done
+        let last = addr.checked_add((bytes.len() as u64).saturating_sub(1));
+        let end = self.debugger.machine.mem.len().max(EDIT_LIMIT) as u64;
+        if last.is_none_or(|last| last / self.cell_bytes >= end) {
+            return String::from("E01");
+        }
+        for (addr, byte) in (addr..).zip(bytes) {
+            let cell = (addr / self.cell_bytes) as usize;
+            let shift = 8 * (addr % self.cell_bytes);
+            let value = self.debugger.cell(cell) & !(0xff << shift) | byte << shift;
+            if self.debugger.set_cell(cell, value).is_err() {
+                return String::from("E01");
+            }
+        }
+        String::from("OK")
+    }
+
+    /// Handle `TYPE,ADDR,KIND` for inserting or removing a breakpoint. Software and hardware
+    /// breakpoints are both set on the command at or after source offset `ADDR`, and write
+    /// watchpoints on every cell the `KIND` bytes at `ADDR` touch, up to `WATCH_SIZE` bytes.
+    fn set_breakpoint(&mut self, insert: bool, arg: &str) -> String {
+        let mut parts = arg.split(',');
+        let (kind, addr, len) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(kind), Some(addr), Some(len)) => {
+                match (parse_hex(addr.as_bytes()), parse_hex(len.as_bytes())) {
+                    (Some(addr), Some(len)) => (kind, addr, len),
+                    _ => return String::from("E01"),
+                }
+            }
+            _ => return String::from("E01"),
+        };
+        match kind {
+            "0" | "1" if insert => match self.debugger.add_breakpoint(addr as usize) {
+                Some(_) => String::from("OK"),
+                None => String::from("E01"),
+            },
+            "0" | "1" => {
+                self.debugger.remove_breakpoint(addr as usize);
+                String::from("OK")
+            }
+            // EINVAL, like gdbserver replies to watchpoints it can't set
+            "2" if len > WATCH_SIZE => String::from("E22"),
+            "2" => {
+                let first = addr / self.cell_bytes;
+                let last = addr.saturating_add(len.max(1) - 1) / self.cell_bytes;
+                for cell in first..=last {
+                    match insert {
+                        true => self.debugger.add_watchpoint(cell as usize),
+                        false => _ = self.debugger.remove_watchpoint(cell as usize),
+                    }
+                }
+                String::from("OK")
+            }
+            // Read and access watchpoints aren't supported
+            _ => String::new(),
+        }
+    }
+
+    /// Continue forwards or backwards until something stops the program, checking for an
+    /// interrupt from gdb every so often
+    fn resume(&mut self, backwards: bool) -> Result<String, Error> {
+        loop {
+            let result = match backwards {
+                true => Ok(self.debugger.step_back(CHUNK)),
+                false => self.debugger.step(CHUNK, &mut self.input, &mut self.output),
+            };
+            match result {
+                Ok(Pause::Stepped) if self.connection.interrupted() => {
+                    self.send_output()?;
+                    return Ok(format!("S{:02x}", SIGINT));
+                }
+                Ok(Pause::Stepped) => {}
+                result => return self.stop_reply(result),
+            }
+        }
+    }
+
+    /// Describe why the program stopped, sending its output and any error to gdb first
+    fn stop_reply(&mut self, result: Result<Pause, Error>) -> Result<String, Error> {
+        self.send_output()?;
+        Ok(match result {
+            Ok(Pause::Stepped) => format!("S{:02x}", SIGTRAP),
+            Ok(Pause::Breakpoint(_)) => format!("T{:02x}swbreak:;", SIGTRAP),
+            Ok(Pause::Watchpoint { cell, .. }) => {
+                format!("T{:02x}watch:{:x};", SIGTRAP, cell as u64 * self.cell_bytes)
+            }
+            Ok(Pause::Start) => format!("T{:02x}replaylog:begin;", SIGTRAP),
+            Ok(Pause::Finished) => String::from("W00"),
+            Err(err) => {
+                self.send_console(&err.report(self.code))?;
+                let signal = match err {
+                    Error::Runtime { fault: Fault::PointerUnderflow, .. } => SIGSEGV,
+                    Error::Runtime { fault: Fault::CellOverflow, .. } => SIGFPE,
+                    Error::Runtime { fault: Fault::InvalidChar, .. } => SIGILL,
+                    _ => SIGPIPE,
+                };
+                format!("S{:02x}", signal)
+            }
+        })
+    }
+
+    /// Handle a `monitor` command, given hex-encoded
+    fn monitor(&mut self, arg: &str) -> Result<String, Error> {
+        let bytes: Option<Vec<u8>> = arg
+            .as_bytes()
+            .chunks(2)
+            .map(|digits| parse_hex(digits).map(|byte| byte as u8))
+            .collect();
+        let command = bytes.map(|bytes| String::from_utf8_lossy(&bytes).into_owned());
+        match command.as_deref().map(str::trim) {
+            Some("restart") => {
+                self.debugger.restart();
+                let debug = &self.args.debug;
+                self.input = open_input(debug.input.as_ref(), &debug.options)?;
+                self.send_console("Restarted the program\n")?;
+            }
+            _ => self.send_console("Supported monitor commands: restart\n")?,
+        }
+        Ok(String::from("OK"))
+    }
+
+    /// Send what the program wrote since the last stop as console output
+    fn send_output(&mut self) -> Result<(), Error> {
+        let bytes = std::mem::take(self.output.get_mut());
+        for chunk in bytes.chunks(PACKET_SIZE / 2 - 1) {
+            self.connection.send(&format!("O{}", hex(chunk)))?;
+        }
+        Ok(())
+    }
+
+    fn send_console(&mut self, message: &str) -> Result<(), Error> {
+        for chunk in message.as_bytes().chunks(PACKET_SIZE / 2 - 1) {
+            self.connection.send(&format!("O{}", hex(chunk)))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packets_sent_before_ack_are_kept() {
+        // gdb sends another packet and an interrupt before acknowledging the reply. The `+`
+        // inside the packet isn't an acknowledgement.
+        let incoming = b"$qSupported:swbreak+#8b\x03+".to_vec();
+        let mut connection =
+            Connection::new(Box::new(std::io::Cursor::new(incoming)), Box::new(std::io::sink()));
+        connection.send("OK").unwrap();
+        match connection.receive().unwrap() {
+            Some(Packet::Command(command)) => assert_eq!(command, "qSupported:swbreak+"),
+            _ => panic!("expected the packet sent before the ack"),
+        }
+        assert!(matches!(connection.receive().unwrap(), Some(Packet::Interrupt)));
+        assert!(connection.receive().unwrap().is_none());
+    }
+}
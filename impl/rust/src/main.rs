@@ -1,32 +1,233 @@
 use std::fs;
+use std::io::{Read, Write};
 use std::path::PathBuf;
-use clap::Parser;
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+use clap::{Args, Parser, Subcommand};
+use brainfetch_core::cell::Cell;
+use brainfetch_core::coverage::Coverage;
+use brainfetch_core::{command_opt, io, Error, Limits, Machine, OptLevel, Profile, Program};
 
-mod command;
-mod command_opt;
+mod compile;
+mod dap;
+mod debugger;
+mod gdb;
+
+/// Number of loops and instructions listed by `--profile`
+const PROFILE_TOP: usize = 10;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
     /// BrainF*** file to execute
-    file: PathBuf,
+    #[arg(required = true)]
+    file: Option<PathBuf>,
+
+    #[command(flatten)]
+    options: Options,
+
+    /// Stop the program after executing this many instructions
+    #[arg(long, value_name = "INSTRUCTIONS")]
+    fuel: Option<u64>,
+
+    /// Stop the program once it has been running for this long
+    #[arg(long, value_name = "SECONDS", value_parser = parse_timeout)]
+    timeout: Option<Duration>,
+
+    /// Count how often each instruction and loop runs, and print the hottest ones to stderr once
+    /// the program stops
+    #[arg(long)]
+    profile: bool,
+
+    /// Write the profile as folded stacks, one frame per enclosing loop, for flamegraph.pl or
+    /// inferno to render
+    #[arg(long, value_name = "FILE")]
+    folded_stacks: Option<PathBuf>,
+
+    /// Record which lines and loops ran in this lcov file, adding to the counts already in it.
+    /// Runs the program unoptimized, so it's slower.
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["profile", "folded_stacks"])]
+    coverage: Option<PathBuf>,
+
+    /// Print the source to stderr with how often each line ran, including the runs already in
+    /// the `--coverage` file
+    #[arg(long, conflicts_with_all = ["profile", "folded_stacks"])]
+    annotate: bool,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Step through a program interactively, with breakpoints and watchpoints
+    Debug(debugger::DebugArgs),
+    /// Debug a program from gdb or lldb, by serving it over the GDB remote protocol
+    Gdb(gdb::GdbArgs),
+    /// Debug a program from an editor, by speaking the Debug Adapter Protocol over stdio
+    Dap(dap::DapArgs),
+    /// Compile a program ahead of time, to C source that builds into a standalone executable
+    Compile(compile::CompileArgs),
+}
+
+/// How the program's cells and I/O behave, shared with the subcommands
+#[derive(Args)]
+#[command(about = None, long_about = None)]
+struct Options {
+    /// How cell values are written to stdout
+    #[arg(long, value_enum, default_value_t)]
+    output_encoding: io::OutputEncoding,
+
+    /// What `,` stores in the cell once stdin is exhausted
+    #[arg(long, value_enum, default_value_t)]
+    eof: io::EofBehavior,
+
+    /// Fail on stdin read errors instead of treating them like the end of input
+    #[arg(long)]
+    strict_input: bool,
+
+    /// Width of each cell on the tape, in bits
+    #[arg(long, default_value_t = 8, value_parser = parse_cell_bits)]
+    cell_bits: u32,
+
+    /// What happens when a cell goes above its maximum value or below 0
+    #[arg(long, value_enum, default_value_t)]
+    overflow: command_opt::OverflowMode,
 }
 
-fn main() -> Result<(), &'static str> {
+fn parse_cell_bits(arg: &str) -> Result<u32, String> {
+    match arg.parse() {
+        Ok(bits @ (8 | 16 | 32 | 64)) => Ok(bits),
+        _ => Err(String::from("cell width must be 8, 16, 32 or 64")),
+    }
+}
+
+fn parse_timeout(arg: &str) -> Result<Duration, String> {
+    arg.parse()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .ok_or_else(|| String::from("timeout must be a non-negative number of seconds"))
+}
+
+fn main() -> ExitCode {
     let cli = Cli::parse();
+    // The editor picks the program once the adapter is running
+    if let Some(Commands::Dap(args)) = &cli.command {
+        return match dap::run(args) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(err) => {
+                eprint!("{}", err.report(""));
+                ExitCode::from(err.exit_code())
+            }
+        };
+    }
 
-    let contents: String = match fs::read_to_string(&cli.file) {
+    let file = match &cli.command {
+        Some(Commands::Debug(args)) => &args.file,
+        Some(Commands::Gdb(args)) => &args.debug.file,
+        Some(Commands::Compile(args)) => &args.file,
+        Some(Commands::Dap(_)) => unreachable!("handled above"),
+        None => cli.file.as_ref().expect("clap requires a file without a subcommand"),
+    };
+
+    let contents: String = match fs::read_to_string(file) {
         Ok(data) => data,
-        Err(err) => {
-            eprintln!("Can't open file '{}': {}", cli.file.to_string_lossy(), err);
-            return Err("Unable to open specified file.")
+        Err(source) => {
+            let err = Error::Open { path: file.clone(), source };
+            eprint!("{}", err.report(""));
+            return ExitCode::from(err.exit_code());
         }
     };
 
-    let program = command_opt::parse(&contents)?;
-    
-    command_opt::execute(&program)?;
+    let result = match &cli.command {
+        Some(Commands::Debug(args)) => debugger::run(args, &contents),
+        Some(Commands::Gdb(args)) => gdb::run(args, &contents),
+        Some(Commands::Compile(args)) => compile::run(args, &contents),
+        Some(Commands::Dap(_)) => unreachable!("handled above"),
+        None => run(&cli, &contents),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprint!("{}", err.report(&contents));
+            ExitCode::from(err.exit_code())
+        }
+    }
+}
+
+fn run(cli: &Cli, contents: &str) -> Result<(), Error> {
+    let options = &cli.options;
+    // Coverage needs a count for every command, which the optimizer would merge or remove
+    let covering = cli.coverage.is_some() || cli.annotate;
+    let opt_level = if covering { OptLevel::None } else { OptLevel::Full };
+    let program = command_opt::parse_with(contents, options.cell_bits, options.overflow, opt_level)
+        .map_err(Error::Parse)?;
 
-    Ok(())
+    let mut input = io::Input::stdin(options.eof, options.strict_input);
+    let mut output = io::Output::stdout(options.output_encoding);
+    let limits = Limits {
+        fuel: cli.fuel,
+        deadline: cli.timeout.map(|timeout| Instant::now() + timeout),
+    };
+    if cli.profile || cli.folded_stacks.is_some() || covering {
+        return match options.cell_bits {
+            8 => profile::<u8>(cli, contents, &program, &limits, &mut input, &mut output),
+            16 => profile::<u16>(cli, contents, &program, &limits, &mut input, &mut output),
+            32 => profile::<u32>(cli, contents, &program, &limits, &mut input, &mut output),
+            _ => profile::<u64>(cli, contents, &program, &limits, &mut input, &mut output),
+        };
+    }
+    let (prg, overflow) = (&program, options.overflow);
+    match options.cell_bits {
+        8 => Machine::<u8>::new().run(prg, overflow, &limits, &mut input, &mut output),
+        16 => Machine::<u16>::new().run(prg, overflow, &limits, &mut input, &mut output),
+        32 => Machine::<u32>::new().run(prg, overflow, &limits, &mut input, &mut output),
+        _ => Machine::<u64>::new().run(prg, overflow, &limits, &mut input, &mut output),
+    }
 }
 
+/// Run `program` on a tape of `C` cells while counting how often each instruction executes,
+/// then report the counts, or the coverage they add up to, in the ways asked for
+fn profile<C: Cell>(
+    cli: &Cli,
+    contents: &str,
+    program: &Program,
+    limits: &Limits,
+    input: &mut io::Input<impl Read>,
+    output: &mut io::Output<impl Write>,
+) -> Result<(), Error> {
+    let (overflow, mut profile) = (cli.options.overflow, Profile::new(program));
+    let mut machine = Machine::<C>::new();
+    let result = machine.profile(program, overflow, limits, &mut profile, input, output);
+    // Report even if the program failed, since a profile of one that timed out is just as useful
+    if cli.profile {
+        eprint!("{}", profile.report(program, contents, PROFILE_TOP));
+    }
+    let file = cli.file.as_ref().expect("clap requires a file without a subcommand");
+    if let Some(path) = &cli.folded_stacks {
+        let root = file.file_name().unwrap_or(file.as_os_str()).to_string_lossy();
+        fs::write(path, profile.folded(program, contents, &root))
+            .map_err(|source| Error::Write { path: path.clone(), source })?;
+    }
+    if cli.coverage.is_some() || cli.annotate {
+        let mut coverage = Coverage::new(&profile, program, contents);
+        if let Some(path) = &cli.coverage {
+            // Records are keyed by absolute path, so runs from different directories add up
+            let source = std::path::absolute(file).unwrap_or_else(|_| file.clone());
+            let source = source.to_string_lossy();
+            let lcov = match fs::read_to_string(path) {
+                Ok(lcov) => lcov,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+                Err(source) => return Err(Error::Open { path: path.clone(), source }),
+            };
+            coverage.merge_lcov(&lcov, &source);
+            fs::write(path, coverage.update_lcov(&lcov, &source))
+                .map_err(|source| Error::Write { path: path.clone(), source })?;
+        }
+        if cli.annotate {
+            eprint!("{}", coverage.annotate(contents));
+        }
+    }
+    result
+}